@@ -5,7 +5,12 @@
 /// push and pop operations.
 /// 
 pub mod sorted_queue {
-    use std::collections::HashMap;
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::collections::hash_map::RandomState;
+    use std::cell::{Cell, OnceCell};
+    use std::cmp::Ordering;
+    use std::hash::BuildHasher;
+    use std::rc::Rc;
 
     ///
     /// Custom error type returned if
@@ -23,6 +28,436 @@ pub mod sorted_queue {
 
     impl std::error::Error for NotInQueue {}
 
+    ///
+    /// Error type returned by `enq_checked`
+    /// when the queue has grown past its
+    /// soft limit. The value is still
+    /// inserted.
+    /// 
+    #[derive(Debug)]
+    pub struct OverSoftLimit;
+
+    impl std::fmt::Display for OverSoftLimit {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Queue size exceeds its soft limit")
+        }
+    }
+
+    impl std::error::Error for OverSoftLimit {}
+
+    ///
+    /// Custom error type returned if an
+    /// operation would leave the same
+    /// reference in the queue twice.
+    /// 
+    #[derive(Debug)]
+    pub struct DuplicateKey;
+
+    impl std::fmt::Display for DuplicateKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Reference already present in queue")
+        }
+    }
+
+    impl std::error::Error for DuplicateKey {}
+
+    ///
+    /// Error type returned by `enq_guarded`
+    /// when the enqueue guard refuses the
+    /// reference.
+    /// 
+    #[derive(Debug)]
+    pub struct RejectedByGuard;
+
+    impl std::fmt::Display for RejectedByGuard {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Reference rejected by enqueue guard")
+        }
+    }
+
+    impl std::error::Error for RejectedByGuard {}
+
+    ///
+    /// A single problem found by `diagnose`.
+    /// 
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum InvariantViolation {
+        /// The child would come out of the
+        /// queue before its parent.
+        HeapOrder { parent: usize, child: usize },
+        /// The map indexes a reference to a
+        /// slot that does not hold it.
+        MapMismatch { slot: usize },
+        /// No map entry points at this heap slot.
+        OrphanedEntry { index: usize },
+    }
+
+    impl std::fmt::Display for InvariantViolation {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                InvariantViolation::HeapOrder { parent, child } => write!(
+                    f, "heap slot {} comes out before its parent at slot {}", child, parent
+                ),
+                InvariantViolation::MapMismatch { slot } => write!(
+                    f, "map points a reference at heap slot {}, which does not hold it", slot
+                ),
+                InvariantViolation::OrphanedEntry { index } => write!(
+                    f, "heap slot {} is not indexed by the map", index
+                ),
+            }
+        }
+    }
+
+    ///
+    /// What `verify_and_repair` had to fix.
+    /// A default, all-false report means the
+    /// queue was already healthy.
+    /// 
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct RepairReport {
+        /// The heap order was broken and the
+        /// heap was rebuilt.
+        pub heap_reordered: bool,
+        /// The map disagreed with the heap and
+        /// was rebuilt from it.
+        pub map_rebuilt: bool,
+        /// Number of duplicate heap entries
+        /// that were dropped.
+        pub entries_dropped: usize,
+        /// Number of heap entries whose
+        /// reference was missing from the map
+        /// and that were dropped.
+        pub orphans_dropped: usize,
+    }
+
+    impl RepairReport {
+        ///
+        /// Returns true if nothing was repaired.
+        /// 
+        pub fn is_empty(&self) -> bool {
+            *self == RepairReport::default()
+        }
+    }
+
+    ///
+    /// Allocated capacities of the heap
+    /// vector and of the index map.
+    /// 
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CapacityReport {
+        pub heap: usize,
+        pub map: usize,
+    }
+
+    ///
+    /// The best entry, the worst entry and
+    /// the remaining queue, as returned by
+    /// `take_extremes`.
+    /// 
+    pub type Extremes<'a, T, F, S> =
+        (Option<(T, &'a F)>, Option<(T, &'a F)>, SortedQueue<'a, T, F, S>);
+
+    ///
+    /// The best batch and the worst batch
+    /// removed by `trim`.
+    /// 
+    pub type Trimmed<'a, T, F> = (Vec<(T, &'a F)>, Vec<(T, &'a F)>);
+
+    type Comparator<'a, T, F> = Rc<dyn Fn(&(T, &'a F), &(T, &'a F)) -> bool>;
+    type Guard<'a, F> = Rc<dyn Fn(&'a F) -> bool>;
+    type PriorityFn<'a, T, F> = Rc<dyn Fn(&'a F) -> T>;
+
+    ///
+    /// Says whether priority `a` comes out
+    /// before `b` in a max-queue or a
+    /// min-queue. A value that does not
+    /// compare equal to itself, like an `f64`
+    /// NaN, comes out after every other value
+    /// in either orientation, and all such
+    /// values tie with each other.
+    /// 
+    pub(crate) fn comes_before<T: PartialOrd>(a: &T, b: &T, max: bool) -> bool {
+        match a.partial_cmp(b) {
+            Some(Ordering::Less) => !max,
+            Some(Ordering::Greater) => max,
+            Some(Ordering::Equal) => false,
+            None => a.partial_cmp(a).is_some() && b.partial_cmp(b).is_none(),
+        }
+    }
+
+    ///
+    /// Hands out the next insertion number
+    /// of a stable queue. When the numbers
+    /// run out, the ones in `seq` are
+    /// renumbered from zero in their current
+    /// order first.
+    /// 
+    pub(crate) fn next_stamp<K: Eq + std::hash::Hash>(seq: &mut HashMap<K, u64>, next_seq: &mut u64) -> u64 {
+        let next = match next_seq.checked_add(1) {
+            Some(next) => next,
+            None => {
+                let mut stamps: Vec<(K, u64)> = seq.drain().collect();
+                stamps.sort_unstable_by_key(|(_, n)| *n);
+                for (n, (key, _)) in stamps.into_iter().enumerate() {
+                    seq.insert(key, n as u64);
+                }
+                *next_seq = seq.len() as u64;
+                *next_seq + 1
+            }
+        };
+        let stamp = *next_seq;
+        *next_seq = next;
+        stamp
+    }
+
+    ///
+    /// Sifts the last element of a plain
+    /// vector heap up, where `before` says
+    /// whether the first argument belongs
+    /// closer to the root.
+    /// 
+    fn push_by<E>(heap: &mut Vec<E>, item: E, before: &dyn Fn(&E, &E) -> bool) {
+        heap.push(item);
+        let mut index = heap.len() - 1;
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if !before(&heap[index], &heap[parent]) {
+                break;
+            }
+            heap.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    ///
+    /// Replaces the root of a plain vector
+    /// heap and sifts it back down.
+    /// 
+    fn replace_root_by<E>(heap: &mut [E], item: E, before: &dyn Fn(&E, &E) -> bool) {
+        heap[0] = item;
+        let mut index = 0;
+        loop {
+            let mut next = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < heap.len() && before(&heap[child], &heap[next]) {
+                    next = child;
+                }
+            }
+            if next == index {
+                break;
+            }
+            heap.swap(index, next);
+            index = next;
+        }
+    }
+
+    ///
+    /// An `f64` priority ordered by
+    /// `f64::total_cmp`, so NaN, negative zero
+    /// and subnormals all have a fixed place
+    /// in the queue.
+    /// 
+    #[derive(Debug, Clone, Copy)]
+    pub struct OrderedFloat(pub f64);
+
+    impl PartialEq for OrderedFloat {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == Ordering::Equal
+        }
+    }
+
+    impl Eq for OrderedFloat {}
+
+    impl PartialOrd for OrderedFloat {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for OrderedFloat {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0.total_cmp(&other.0)
+        }
+    }
+
+    impl From<f64> for OrderedFloat {
+        fn from(value: f64) -> Self {
+            OrderedFloat(value)
+        }
+    }
+
+    impl From<OrderedFloat> for f64 {
+        fn from(value: OrderedFloat) -> Self {
+            value.0
+        }
+    }
+
+    ///
+    /// Handle to an entry returned by
+    /// `enq_handle`. It remembers where the
+    /// entry was last seen in the heap so
+    /// `change_by_handle` can usually skip
+    /// the map lookup.
+    /// 
+    #[derive(Debug)]
+    pub struct Handle<'a, F> {
+        data: &'a F,
+        index: Cell<usize>,
+    }
+
+    ///
+    /// Collects the settings for a new queue,
+    /// started from `SortedQueue::builder`.
+    /// Without further calls it builds the
+    /// same min-queue as `SortedQueue::new`.
+    /// 
+    pub struct PriorityQueueBuilder<'a, T, F> {
+        max: bool,
+        capacity: usize,
+        comparator: Option<Comparator<'a, T, F>>,
+        bound: Option<usize>,
+        stable: bool,
+    }
+
+    impl<'a, T, F> PriorityQueueBuilder<'a, T, F>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash
+    {
+        ///
+        /// Larger priorities come out first.
+        /// 
+        pub fn max(mut self) -> Self {
+            self.max = true;
+            self
+        }
+
+        ///
+        /// Smaller priorities come out first.
+        /// 
+        pub fn min(mut self) -> Self {
+            self.max = false;
+            self
+        }
+
+        ///
+        /// Reserves room for `n` entries.
+        /// 
+        pub fn capacity(mut self, n: usize) -> Self {
+            self.capacity = n;
+            self
+        }
+
+        ///
+        /// Orders the queue by a comparator
+        /// instead of the orientation; priorities
+        /// that compare `Less` come out first.
+        /// 
+        pub fn comparator(mut self, comp: impl Fn(&T, &T) -> Ordering + 'static) -> Self {
+            self.comparator = Some(Rc::new(move |x, y| comp(&x.0, &y.0) == Ordering::Less));
+            self
+        }
+
+        ///
+        /// Keeps at most `cap` elements, as in
+        /// `SortedQueue::with_capacity_bounded`.
+        /// 
+        pub fn bounded(mut self, cap: usize) -> Self {
+            self.bound = Some(cap);
+            self
+        }
+
+        ///
+        /// Breaks ties between equal priorities
+        /// by insertion order, as in
+        /// `SortedQueue::new_stable`.
+        /// 
+        pub fn stable(mut self) -> Self {
+            self.stable = true;
+            self
+        }
+
+        ///
+        /// Builds the configured queue.
+        /// 
+        pub fn build(self) -> SortedQueue<'a, T, F> {
+            let mut queue = SortedQueue::new(self.max);
+            if let Some(comp) = self.comparator {
+                queue.comp = comp;
+                queue.custom = true;
+            }
+            queue.bound = self.bound;
+            queue.stable = self.stable;
+            queue.heap.reserve(self.capacity);
+            queue.map.reserve(self.capacity);
+            queue
+        }
+    }
+
+    ///
+    /// Consuming iterator over the entries of
+    /// a queue in dequeue order, returned by
+    /// `drain_sorted`. `next` yields the best
+    /// entry left and `next_back` the worst.
+    /// 
+    pub struct DrainSorted<'a, T, F> {
+        entries: std::vec::IntoIter<(T, &'a F)>,
+    }
+
+    impl<'a, T, F> Iterator for DrainSorted<'a, T, F> {
+        type Item = (T, &'a F);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.entries.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.entries.size_hint()
+        }
+    }
+
+    impl<'a, T, F> DoubleEndedIterator for DrainSorted<'a, T, F> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.entries.next_back()
+        }
+    }
+
+    impl<'a, T, F> ExactSizeIterator for DrainSorted<'a, T, F> {}
+
+    ///
+    /// Iterator returned by `drain` that
+    /// dequeues entries in priority order.
+    /// Dropping it early leaves the rest of
+    /// the entries in the queue.
+    /// 
+    pub struct SortedDrain<'q, 'a, T, F, S = RandomState>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash
+    {
+        queue: &'q mut SortedQueue<'a, T, F, S>,
+    }
+
+    impl<'q, 'a, T, F, S> Iterator for SortedDrain<'q, 'a, T, F, S>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash,
+          S: BuildHasher
+    {
+        type Item = (T, &'a F);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.queue.deq()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (self.queue.size(), Some(self.queue.size()))
+        }
+    }
+
+    impl<'q, 'a, T, F, S> ExactSizeIterator for SortedDrain<'q, 'a, T, F, S>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash,
+          S: BuildHasher
+    {}
+
     ///
     /// A sorted priority queue that uses
     /// either a max heap or a min heap.
@@ -32,130 +467,585 @@ pub mod sorted_queue {
     /// stores the value it is compared by and
     /// the index of the reference associated
     /// with the value.
+    /// The map can be given a custom hasher
+    /// through `with_hasher`.
     /// 
-    pub struct SortedQueue<'a, T, F>
-    where T: PartialOrd + Ord + Copy,
+    pub struct SortedQueue<'a, T, F, S = RandomState>
+    where T: PartialOrd + Clone,
           F: Eq + std::hash::Hash
     {
         heap: Vec<(T, &'a F)>,
-        comp: Box<dyn Fn((T, &'a F), (T, &'a F)) -> bool>,
-        map: HashMap<&'a F, usize>,
+        comp: Comparator<'a, T, F>,
+        map: HashMap<&'a F, usize, S>,
+        max: bool,
+        soft_limit: Option<usize>,
+        enq_guard: Option<Guard<'a, F>>,
+        growth_events: usize,
+        sorted: OnceCell<Vec<(T, &'a F)>>,
+        #[cfg(test)]
+        sorted_builds: Cell<usize>,
+        lazy: HashMap<&'a F, PriorityFn<'a, T, F>>,
+        bound: Option<usize>,
+        custom: bool,
+        reversed: bool,
+        stable: bool,
+        seq: HashMap<&'a F, u64>,
+        next_seq: u64,
     }
 
     impl<'a, T, F> SortedQueue<'a, T, F>
-    where T: PartialOrd + Ord + Copy,
+    where T: PartialOrd + Clone,
           F: Eq + std::hash::Hash
     {
         ///
         /// Returns a new, blank priority queue.
+        /// Priorities only need `PartialOrd`, so
+        /// `f64` works directly; a NaN priority
+        /// comes out after every other value, in
+        /// a max-queue as well as a min-queue.
         /// 
         pub fn new(max: bool) -> SortedQueue<'a, T, F> {
-            if max {
-                SortedQueue {
-                    heap: vec![],
-                    comp: Box::new(|x, y| {
-                        let (a, _) = x;
-                        let (b, _) = y;
-                        a > b
-                    }),
-                    map: HashMap::new(),
-                }
-            } else {
-                SortedQueue {
-                    heap: vec![],
-                    comp: Box::new(|x, y| {
-                        let (a, _) = x;
-                        let (b, _) = y;
-                        a < b
-                    }),
-                    map: HashMap::new(),
-                }
+            let mut queue = SortedQueue::new_by(move |x: &(T, &'a F), y: &(T, &'a F)| {
+                comes_before(&x.0, &y.0, max)
+            });
+            queue.max = max;
+            queue.custom = false;
+            queue
+        }
+
+        ///
+        /// Returns a new, blank queue ordered by
+        /// `comp`, which gets two entries and
+        /// returns true when the first one should
+        /// come out before the second. It must be
+        /// a consistent strict ordering: never
+        /// true for an entry against itself or
+        /// both ways round, and transitive, or
+        /// the heap will come out of order.
+        /// 
+        pub fn new_by<G>(comp: G) -> SortedQueue<'a, T, F>
+        where G: Fn(&(T, &'a F), &(T, &'a F)) -> bool + 'static
+        {
+            let mut queue = SortedQueue::with_hasher(false, RandomState::new());
+            queue.comp = Rc::new(comp);
+            queue.custom = true;
+            queue
+        }
+
+        ///
+        /// Returns a new, blank queue in stable
+        /// mode: entries with equal priorities
+        /// come out in the order they were first
+        /// inserted. Changing a priority keeps
+        /// the entry's place among its ties.
+        /// 
+        pub fn new_stable(max: bool) -> SortedQueue<'a, T, F> {
+            let mut queue = SortedQueue::new(max);
+            queue.stable = true;
+            queue
+        }
+
+        ///
+        /// Returns a new, blank queue with room
+        /// for `cap` elements in both the heap
+        /// and the map.
+        /// 
+        pub fn with_capacity(max: bool, cap: usize) -> SortedQueue<'a, T, F> {
+            let mut queue = SortedQueue::new(max);
+            queue.heap = Vec::with_capacity(cap);
+            queue.map = HashMap::with_capacity(cap);
+            queue
+        }
+
+        ///
+        /// Returns a new queue that never holds
+        /// more than `cap` elements. Once it is
+        /// full, `enq` keeps the better of the
+        /// new entry and the worst one held and
+        /// returns the other. Finding the worst
+        /// entry scans the leaves of the heap, so
+        /// an `enq` on a full queue costs O(n).
+        /// 
+        pub fn with_capacity_bounded(max: bool, cap: usize) -> SortedQueue<'a, T, F> {
+            let mut queue = SortedQueue::new(max);
+            queue.bound = Some(cap);
+            queue.heap.reserve(cap);
+            queue.map.reserve(cap);
+            queue
+        }
+
+        ///
+        /// Returns a new queue already holding
+        /// one value and reference.
+        /// 
+        pub fn with_initial(max: bool, value: T, data: &'a F) -> SortedQueue<'a, T, F> {
+            let mut queue = SortedQueue::new(max);
+            queue.enq(value, data);
+            queue
+        }
+
+        ///
+        /// Builds a queue from all the pairs at
+        /// once with Floyd's heap construction,
+        /// in O(n) rather than the O(n log n) of
+        /// repeated `enq`. If a reference shows
+        /// up more than once the last pair wins.
+        /// 
+        pub fn from_pairs(max: bool, pairs: Vec<(T, &'a F)>) -> SortedQueue<'a, T, F> {
+            let mut queue = SortedQueue::new(max);
+            queue.load(pairs);
+            queue
+        }
+
+        ///
+        /// Returns a builder for configuring a
+        /// new queue step by step.
+        /// 
+        pub fn builder() -> PriorityQueueBuilder<'a, T, F> {
+            PriorityQueueBuilder {
+                max: false,
+                capacity: 0,
+                comparator: None,
+                bound: None,
+                stable: false,
+            }
+        }
+
+        ///
+        /// Collects the pairs into a min-queue,
+        /// heapifying once. If a reference shows
+        /// up more than once the last pair wins.
+        /// 
+        pub fn from_iter_min(iter: impl IntoIterator<Item = (T, &'a F)>)
+            -> SortedQueue<'a, T, F> {
+            let mut queue = SortedQueue::new(false);
+            queue.load(iter);
+            queue
+        }
+
+        ///
+        /// Collects the pairs into a max-queue,
+        /// heapifying once. If a reference shows
+        /// up more than once the last pair wins.
+        /// 
+        pub fn from_iter_max(iter: impl IntoIterator<Item = (T, &'a F)>)
+            -> SortedQueue<'a, T, F> {
+            let mut queue = SortedQueue::new(true);
+            queue.load(iter);
+            queue
+        }
+    }
+
+    impl<'a, F> SortedQueue<'a, OrderedFloat, F>
+    where F: Eq + std::hash::Hash
+    {
+        ///
+        /// Returns a new, blank queue of float
+        /// priorities ordered by `total_cmp`.
+        /// 
+        pub fn new_float(max: bool) -> SortedQueue<'a, OrderedFloat, F> {
+            SortedQueue::new(max)
+        }
+    }
+
+    impl<'a, T, F, S> SortedQueue<'a, T, F, S>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash,
+          S: BuildHasher
+    {
+        ///
+        /// Returns a new, blank priority queue
+        /// whose map uses the given hasher.
+        /// 
+        pub fn with_hasher(max: bool, hasher: S) -> SortedQueue<'a, T, F, S> {
+            let comp: Comparator<'a, T, F> = Rc::new(move |x, y| comes_before(&x.0, &y.0, max));
+            SortedQueue {
+                heap: vec![],
+                comp,
+                map: HashMap::with_hasher(hasher),
+                max,
+                soft_limit: None,
+                enq_guard: None,
+                growth_events: 0,
+                sorted: OnceCell::new(),
+                #[cfg(test)]
+                sorted_builds: Cell::new(0),
+                lazy: HashMap::new(),
+                bound: None,
+                custom: false,
+                reversed: false,
+                stable: false,
+                seq: HashMap::new(),
+                next_seq: 0,
             }
         }
 
+        ///
+        /// Returns an empty queue ordered the same
+        /// way as this one, with the same bound
+        /// and the insertion numbers of stable
+        /// mode, so entries moved into it keep
+        /// their place among ties.
+        /// 
+        fn empty_like(&self) -> SortedQueue<'a, T, F, S>
+        where S: Clone
+        {
+            let mut queue = SortedQueue::with_hasher(self.max, self.map.hasher().clone());
+            queue.comp = Rc::clone(&self.comp);
+            queue.custom = self.custom;
+            queue.reversed = self.reversed;
+            queue.bound = self.bound;
+            queue.stable = self.stable;
+            queue.seq = self.seq.clone();
+            queue.next_seq = self.next_seq;
+            queue
+        }
+
+        fn invalidate(&mut self) {
+            self.sorted.take();
+        }
+
         fn swap(&mut self, i1: usize, i2: usize) {
-            let (_, o1) = self.heap[i1];
-            let (_, o2) = self.heap[i2];
+            self.invalidate();
+            let o1 = self.heap[i1].1;
+            let o2 = self.heap[i2].1;
             self.map.insert(o1, i2);
             self.map.insert(o2, i1);
-            let temp = self.heap[i1];
-            self.heap[i1] = self.heap[i2];
-            self.heap[i2] = temp;
+            self.heap.swap(i1, i2);
         }
 
-        fn sift_down(&mut self, index: usize) {
+        fn sift_down(&mut self, index: usize) -> usize {
             if self.heap.len() <= 1 {
-                return;
+                return index;
             }
             let mut index = index;
-            let mut swap_index = index;
             loop {
-                swap_index = 2 * index + 1;
+                let swap_index = 2 * index + 1;
                 if swap_index >= self.heap.len() {
                     break;
                 }
                 if swap_index + 1 == self.heap.len() 
-                    || (self.comp)(self.heap[swap_index], self.heap[swap_index + 1]) {
-                    if (self.comp)(self.heap[swap_index], self.heap[index]) {
+                    || self.before(&self.heap[swap_index], &self.heap[swap_index + 1]) {
+                    if self.before(&self.heap[swap_index], &self.heap[index]) {
                         self.swap(index, swap_index);
                         index = swap_index;
                     } else {
                         break;
                     }
-                } else if (self.comp)(self.heap[swap_index + 1], self.heap[index]) {
+                } else if self.before(&self.heap[swap_index + 1], &self.heap[index]) {
                     self.swap(index, swap_index + 1);
                     index = swap_index + 1;
                 } else {
                     break;
                 }
             }
+            index
         }
 
-        fn sift_up(&mut self, index: usize) {
-            if self.heap.len() <= 1 || index == 0 {
-                return;
-            }
+        fn sift_up(&mut self, index: usize) -> usize {
             let mut index = index;
-            let mut swap_index = index;
             loop {
-                swap_index = (index - 1) / 2;
-                if (self.comp)(self.heap[index], self.heap[swap_index]) {
-                    self.swap(index, swap_index);
-                } else {
+                if index == 0 {
                     break;
                 }
-                if swap_index == 0 {
+                let swap_index = (index - 1) / 2;
+                if self.before(&self.heap[index], &self.heap[swap_index]) {
+                    self.swap(index, swap_index);
+                    index = swap_index;
+                } else {
                     break;
                 }
             }
+            index
         }
 
         ///
-        /// Puts the value and the associated
-        /// referrence in the pritority
-        /// queue.
+        /// Says whether entry `a` comes out
+        /// before `b`, going through the
+        /// comparator the right way round after
+        /// `reverse_order`. In stable mode ties
+        /// go to the entry inserted first, and
+        /// an entry not yet inserted comes last.
         /// 
-        pub fn enq(&mut self, value: T, data: &'a F) {
-            let new_index = self.heap.len();
-            self.heap.push((value, data));
-            self.map.insert(data, new_index);
-            self.sift_up(new_index);
+        fn before(&self, a: &(T, &'a F), b: &(T, &'a F)) -> bool {
+            let (first, second) = if self.reversed { (b, a) } else { (a, b) };
+            if (self.comp)(first, second) {
+                return true;
+            }
+            if !self.stable || (self.comp)(second, first) {
+                return false;
+            }
+            let stamp = |data: &'a F| self.seq.get(data).copied().unwrap_or(u64::MAX);
+            stamp(a.1) < stamp(b.1)
+        }
+
+        fn order(&self, a: &(T, &'a F), b: &(T, &'a F)) -> Ordering {
+            if self.before(a, b) {
+                Ordering::Less
+            } else if self.before(b, a) {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        }
+
+        fn sorted_entries(&self) -> Vec<(T, &'a F)> {
+            let mut entries = self.heap.clone();
+            entries.sort_by(|a, b| self.order(a, b));
+            entries
         }
 
         ///
-        /// Removes the value from the front
+        /// Returns up to `n` of the best entries
+        /// in dequeue order, keeping a bounded
+        /// heap whose root is the worst entry
+        /// kept so far.
+        /// 
+        fn best_entries(&self, n: usize) -> Vec<(T, &'a F)> {
+            let worse = |a: &(T, &'a F), b: &(T, &'a F)| self.before(b, a);
+            let mut kept = Vec::with_capacity(n + 1);
+            if n == 0 {
+                return kept;
+            }
+            for entry in self.heap.iter() {
+                if kept.len() < n {
+                    push_by(&mut kept, entry.clone(), &worse);
+                } else if self.before(entry, &kept[0]) {
+                    replace_root_by(&mut kept, entry.clone(), &worse);
+                }
+            }
+            kept.sort_by(|a, b| self.order(a, b));
+            kept
+        }
+
+        fn set_priority(&mut self, index: usize, new_value: T) -> T {
+            self.move_priority(index, new_value).0
+        }
+
+        ///
+        /// Writes a new priority at `index`, sifts
+        /// it into place and returns the old
+        /// priority with the entry's new index.
+        /// 
+        fn move_priority(&mut self, index: usize, new_value: T) -> (T, usize) {
+            self.invalidate();
+            let data = self.heap[index].1;
+            let old = std::mem::replace(&mut self.heap[index], (new_value, data));
+            let new_index = if self.before(&old, &self.heap[index]) {
+                self.sift_down(index)
+            } else {
+                self.sift_up(index)
+            };
+            (old.0, new_index)
+        }
+
+        fn remove_at(&mut self, index: usize) -> (T, &'a F) {
+            let last = self.heap.len() - 1;
+            self.swap(index, last);
+            let (value, data) = self.heap.remove(last);
+            self.map.remove(data);
+            if !self.lazy.is_empty() {
+                self.lazy.remove(data);
+            }
+            if self.stable {
+                self.seq.remove(data);
+            }
+            if index < self.heap.len() {
+                self.sift_down(index);
+                self.sift_up(index);
+            }
+            (value, data)
+        }
+
+        ///
+        /// Finds the entry that would come out
+        /// of the queue last. It has to be a
+        /// leaf, so only the second half of the
+        /// heap is scanned.
+        /// 
+        fn worst_index(&self) -> Option<usize> {
+            if self.heap.is_empty() {
+                return None;
+            }
+            let mut worst = self.heap.len() / 2;
+            for i in worst + 1..self.heap.len() {
+                if self.before(&self.heap[worst], &self.heap[i]) {
+                    worst = i;
+                }
+            }
+            Some(worst)
+        }
+
+        fn guard_allows(&self, data: &'a F) -> bool {
+            match &self.enq_guard {
+                Some(guard) => guard(data),
+                None => true,
+            }
+        }
+
+        ///
+        /// Gives a new reference the next
+        /// insertion number in stable mode.
+        /// 
+        fn stamp(&mut self, data: &'a F) {
+            if self.stable {
+                let stamp = next_stamp(&mut self.seq, &mut self.next_seq);
+                self.seq.insert(data, stamp);
+            }
+        }
+
+        fn push_unsifted(&mut self, value: T, data: &'a F) {
+            self.invalidate();
+            match self.map.get(data) {
+                Some(i) => self.heap[*i] = (value, data),
+                None => {
+                    self.stamp(data);
+                    self.map.insert(data, self.heap.len());
+                    self.heap.push((value, data));
+                }
+            }
+        }
+
+        fn push_sifted(&mut self, value: T, data: &'a F) {
+            self.invalidate();
+            self.stamp(data);
+            let new_index = self.heap.len();
+            let capacity = self.heap.capacity();
+            self.heap.push((value, data));
+            if self.heap.capacity() > capacity {
+                self.growth_events += 1;
+            }
+            self.map.insert(data, new_index);
+            self.sift_up(new_index);
+        }
+
+        fn load(&mut self, iter: impl IntoIterator<Item = (T, &'a F)>) {
+            let iter = iter.into_iter();
+            self.heap.reserve(iter.size_hint().0);
+            for (value, data) in iter {
+                if self.guard_allows(data) {
+                    self.push_unsifted(value, data);
+                }
+            }
+            self.heapify();
+        }
+
+        fn heapify(&mut self) {
+            self.invalidate();
+            if let Some(bound) = self.bound {
+                if self.heap.len() > bound {
+                    let mut heap = std::mem::take(&mut self.heap);
+                    heap.select_nth_unstable_by(bound, |a, b| self.order(a, b));
+                    heap.truncate(bound);
+                    self.heap = heap;
+                }
+            }
+            self.map.clear();
+            for (i, (_, data)) in self.heap.iter().enumerate() {
+                self.map.insert(*data, i);
+            }
+            if !self.lazy.is_empty() {
+                let map = &self.map;
+                self.lazy.retain(|data, _| map.contains_key(*data));
+            }
+            if self.stable {
+                let map = &self.map;
+                self.seq.retain(|data, _| map.contains_key(*data));
+            }
+            for i in (0..self.heap.len() / 2).rev() {
+                self.sift_down(i);
+            }
+        }
+
+        ///
+        /// Puts the value and the associated
+        /// referrence in the pritority
+        /// queue. If the reference is already
+        /// there its priority is replaced and
+        /// re-sifted instead, so a reference is
+        /// never held twice. Does nothing if an
+        /// enqueue guard is set and refuses the
+        /// data.
+        /// On a full bounded queue, returns the
+        /// entry that was left out: the worst one
+        /// held, or the new one if it is no
+        /// better than that.
+        /// 
+        pub fn enq(&mut self, value: T, data: &'a F) -> Option<(T, &'a F)> {
+            if !self.guard_allows(data) {
+                return None;
+            }
+            if let Some(index) = self.map.get(data) {
+                self.set_priority(*index, value);
+                return None;
+            }
+            if let Some(bound) = self.bound {
+                if self.heap.len() >= bound {
+                    let entry = (value, data);
+                    let worst = match self.worst_index() {
+                        Some(worst) if self.before(&entry, &self.heap[worst]) => worst,
+                        _ => return Some(entry),
+                    };
+                    let evicted = self.remove_at(worst);
+                    self.push_sifted(entry.0, entry.1);
+                    return Some(evicted);
+                }
+            }
+            self.push_sifted(value, data);
+            None
+        }
+
+        ///
+        /// Removes the value from the front
         /// of the queue and returns the value
         /// and associated referrence.
         /// 
         pub fn deq(&mut self) -> Option<(T, &'a F)> {
-            if self.heap.len() == 0 {
+            if self.heap.is_empty() {
                 return None;
             }
-            self.swap(0, self.heap.len()-1);
-            let (rem_val, rem_ref) = self.heap.remove(self.heap.len()-1);
-            self.sift_down(0);
-            self.map.remove(rem_ref);
-            Some((rem_val, rem_ref))
+            Some(self.remove_at(0))
+        }
+
+        ///
+        /// Removes the data object from anywhere
+        /// in the queue and returns its priority,
+        /// failing if it is not in the queue.
+        /// 
+        pub fn remove(&mut self, data: &'a F)
+            -> Result<T, Box<dyn std::error::Error>> {
+            let index = match self.map.get(data) {
+                Some(i) => *i,
+                None => return Err(Box::new(NotInQueue)),
+            };
+            let (value, _) = self.remove_at(index);
+            Ok(value)
+        }
+
+        ///
+        /// Returns the value and reference at
+        /// the front of the queue without
+        /// removing them.
+        /// 
+        pub fn peek(&self) -> Option<(T, &'a F)> {
+            self.heap.first().cloned()
+        }
+
+        ///
+        /// Returns just the priority at the
+        /// front of the queue.
+        /// 
+        pub fn peek_weight(&self) -> Option<T> {
+            self.peek().map(|(value, _)| value)
+        }
+
+        ///
+        /// Returns up to `n` of the front entries
+        /// in priority order without changing the
+        /// queue, using a bounded heap of size `n`.
+        /// 
+        pub fn peek_n(&self, n: usize) -> Vec<(T, &'a F)> {
+            self.best_entries(n)
         }
 
         ///
@@ -163,12 +1053,9 @@ pub mod sorted_queue {
         /// then it is returned along with its weight.
         /// 
         pub fn get_weight(&self, obj: &'a F) -> Option<T> {
-            let heap_index = match self.map.get(obj) {
-                Some(i) => i,
-                None => return None,
-            };
-            let (ret_weight, _) = self.heap[*heap_index];
-            Some(ret_weight)
+            let heap_index = self.map.get(obj)?;
+            let (ret_weight, _) = &self.heap[*heap_index];
+            Some(ret_weight.clone())
         }
 
         ///
@@ -179,20 +1066,55 @@ pub mod sorted_queue {
         /// 
         pub fn change_priority(&mut self, new_value: T, data: &'a F) 
             -> Result<(), Box<dyn std::error::Error>> {
+            self.replace_priority(new_value, data)?;
+            Ok(())
+        }
+
+        ///
+        /// Changes the priority of the data object
+        /// like `change_priority` and returns the
+        /// priority it had before.
+        /// 
+        pub fn replace_priority(&mut self, new_value: T, data: &'a F)
+            -> Result<T, Box<dyn std::error::Error>> {
             let index = match self.map.get(data) {
                 Some(i) => *i,
                 None => return Err(Box::new(NotInQueue)),
             };
-            let (old_val, _) = self.heap[index];
-            self.heap[index] = (new_value, data);
-            if (self.comp)((old_val, data), (new_value, data)) {
-                self.sift_down(index);
-            } else {
-                self.sift_up(index);
-            }
+            Ok(self.set_priority(index, new_value))
+        }
+
+        ///
+        /// Replaces the priority of the data
+        /// object with `f` applied to its current
+        /// one and sifts it into place, failing
+        /// if it is not in the queue.
+        /// 
+        pub fn update_with<G>(&mut self, data: &'a F, f: G)
+            -> Result<(), Box<dyn std::error::Error>>
+        where G: FnOnce(T) -> T
+        {
+            let index = match self.map.get(data) {
+                Some(i) => *i,
+                None => return Err(Box::new(NotInQueue)),
+            };
+            let new_value = f(self.heap[index].0.clone());
+            self.set_priority(index, new_value);
             Ok(())
         }
 
+        ///
+        /// Changes the priority of the data object
+        /// if it is in the queue, and otherwise
+        /// enqueues it. It is the same as `enq`,
+        /// which updates keys it already holds,
+        /// so the enqueue guard and the bound
+        /// apply alike.
+        /// 
+        pub fn upsert(&mut self, value: T, data: &'a F) {
+            self.enq(value, data);
+        }
+
         ///
         /// Tries to find the value in the
         /// queue with the reference, and sets
@@ -204,10 +1126,16 @@ pub mod sorted_queue {
                 Some(i) => *i,
                 None => return Err(Box::new(NotInQueue)),
             };
-            let (old_val, _) = self.heap[index];
-            self.heap[index] = (old_val, new_ref);
+            self.invalidate();
+            self.heap[index].1 = new_ref;
             self.map.remove(data);
             self.map.insert(new_ref, index);
+            if let Some(stamp) = self.seq.remove(data) {
+                self.seq.insert(new_ref, stamp);
+            }
+            if let Some(f) = self.lazy.remove(data) {
+                self.lazy.insert(new_ref, f);
+            }
             Ok(())
         }
 
@@ -218,109 +1146,3699 @@ pub mod sorted_queue {
         pub fn size(&self) -> usize {
             self.heap.len()
         }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::sorted_queue::*;
 
-    use std::collections::HashMap;
+        ///
+        /// Returns the number of elements, the
+        /// same as `size`.
+        /// 
+        pub fn len(&self) -> usize {
+            self.size()
+        }
 
-    #[test]
-    fn compare_tuple() {
-        let x = (4, "hello");
-        let y = (4, "there");
-        println!("{}", x > y);
-    }
+        ///
+        /// Returns true if the queue holds no
+        /// elements.
+        /// 
+        pub fn is_empty(&self) -> bool {
+            self.heap.is_empty()
+        }
 
-    #[derive(Hash, Eq, Debug)]
-    struct Employee<'a> {
-        name: &'a str,
-        id: u64,
-    }
+        ///
+        /// Returns true if the data object is in
+        /// the queue. Only the map is looked at,
+        /// not the heap.
+        /// 
+        pub fn contains(&self, data: &'a F) -> bool {
+            self.map.contains_key(data)
+        }
 
-    impl<'a> PartialEq for Employee<'a> {
-        fn eq(&self, other: &Self) -> bool {
-            self.id == other.id
+        ///
+        /// Empties the queue while keeping its
+        /// configuration: the comparator, hasher,
+        /// soft limit, guard, bound, stable mode
+        /// and the allocated capacity all stay as
+        /// they were. The map is emptied along
+        /// with the heap, so the queue refills
+        /// exactly like a fresh one.
+        /// 
+        pub fn clear(&mut self) {
+            self.invalidate();
+            self.heap.clear();
+            self.map.clear();
+            self.lazy.clear();
+            self.seq.clear();
+            self.next_seq = 0;
         }
-    }
 
-    #[test]
-    fn simple_queue() {
-        let emp_list = vec![
-            Employee { name: "victor", id: 10237 },
-            Employee { name: "hugo", id: 2937 },
-            Employee { name: "marcus", id: 1902 },
-        ];
-        let mut queue = SortedQueue::new(true);
-        for i in 0..3 {
-            queue.enq(i, &emp_list[i]);
+        ///
+        /// Sets a soft limit on the number of
+        /// elements. Going past it never stops
+        /// an insert, but is reported by
+        /// `over_limit` and `enq_checked`.
+        /// 
+        pub fn set_soft_limit(&mut self, limit: usize) {
+            self.soft_limit = Some(limit);
         }
-        for i in 0..3 {
-            let (_, emp) = queue.deq().unwrap();
-            dbg!(emp);
+
+        ///
+        /// Returns true if a soft limit is set
+        /// and the queue holds more elements
+        /// than it allows.
+        /// 
+        pub fn over_limit(&self) -> bool {
+            match self.soft_limit {
+                Some(limit) => self.size() > limit,
+                None => false,
+            }
         }
-    }
 
-    #[derive(PartialEq, Eq, Hash)]
-    struct Node {
-        weight: i32,
-        index: usize,
-    }
+        ///
+        /// Puts the value and reference in the
+        /// queue like `enq`, but returns an
+        /// error if the queue is over its soft
+        /// limit afterwards. The value is
+        /// inserted either way.
+        /// 
+        pub fn enq_checked(&mut self, value: T, data: &'a F)
+            -> Result<(), OverSoftLimit> {
+            self.enq(value, data);
+            if self.over_limit() {
+                return Err(OverSoftLimit);
+            }
+            Ok(())
+        }
 
-    #[test]
-    fn graph_queue() {
-        let mut adjacency_list: Vec<Vec<(usize, i32)>> = vec![
-            vec![(1, 4), (2, 3)],
-            vec![(2, 5), (3, 2)],
-            vec![(3, 7)],
-            vec![(4, 2)],
-            vec![(0, 4), (1, 4), (5, 6)],
-            vec![],
-        ];
-        let mut nodes = vec![];
-        nodes.push(Node {weight: 0, index: 0});
-        for i in 1..adjacency_list.len() {
-            nodes.push(Node {weight: i32::MAX, index: i});
+        ///
+        /// Keeps one entry for every distinct
+        /// priority and returns the others.
+        /// The heap is scanned in index order,
+        /// so the entry with the lowest heap
+        /// index is the one kept.
+        /// 
+        pub fn dedup_by_priority(&mut self) -> Vec<(T, &'a F)>
+        where T: Ord
+        {
+            let mut seen = BTreeSet::new();
+            let mut removed = vec![];
+            self.heap.retain(|entry| {
+                if seen.insert(entry.0.clone()) {
+                    true
+                } else {
+                    removed.push(entry.clone());
+                    false
+                }
+            });
+            self.heapify();
+            removed
         }
-        let mut queue: SortedQueue<i32, Node> = SortedQueue::new(false);
-        for i in 1..adjacency_list.len() {
-            queue.enq(i32::MAX, &nodes[i]);
+
+        ///
+        /// Consumes the queue and returns its
+        /// backing vector so the allocation
+        /// can be reused. The entries are in
+        /// heap order, not sorted.
+        /// 
+        pub fn into_vec_and_reuse(self) -> Vec<(T, &'a F)> {
+            self.heap
         }
-        let mut weights = vec![i32::MAX; 6];
-        weights[0] = 0;
-        let mut path = HashMap::new();
-        path.insert(0, 0);
-        let (mut cur_weight, mut cur_node) = (0, &nodes[0]);
-        loop {
-            for adj in adjacency_list[cur_node.index].iter() {
-                let (index, weight) = adj;
-                let total_weight = weight + cur_weight;
-                if total_weight < weights[*index] {
-                    queue.change_priority(total_weight, &nodes[*index]).unwrap();
-                    weights[*index] = total_weight;
-                    path.insert(*index, cur_node.index);
+
+        ///
+        /// Replaces every reference in the queue
+        /// that appears as a key in the mapping
+        /// with the reference it maps to. Fails
+        /// without changing anything if two
+        /// references would end up equal.
+        /// 
+        pub fn remap_refs(&mut self, mapping: &HashMap<&'a F, &'a F>)
+            -> Result<(), Box<dyn std::error::Error>> {
+            let moves: Vec<(usize, &'a F, &'a F)> = mapping
+                .iter()
+                .filter_map(|(old, new)| {
+                    self.map.get(*old).map(|i| (*i, *old, *new))
+                })
+                .collect();
+            let mut targets = HashSet::new();
+            for (_, _, new) in moves.iter() {
+                let vacated = mapping.contains_key(*new);
+                if (self.map.contains_key(*new) && !vacated) || !targets.insert(*new) {
+                    return Err(Box::new(DuplicateKey));
                 }
             }
-            if queue.size() == 0 {
-                break;
+            for (_, old, _) in moves.iter() {
+                self.map.remove(*old);
             }
-            let cur = queue.deq().unwrap();
-            cur_weight = cur.0;
-            cur_node = cur.1;
+            let carried: Vec<_> = moves
+                .iter()
+                .map(|(_, old, _)| (self.seq.remove(*old), self.lazy.remove(*old)))
+                .collect();
+            self.invalidate();
+            for ((index, _, new), (stamp, f)) in moves.into_iter().zip(carried) {
+                self.heap[index].1 = new;
+                self.map.insert(new, index);
+                if let Some(stamp) = stamp {
+                    self.seq.insert(new, stamp);
+                }
+                if let Some(f) = f {
+                    self.lazy.insert(new, f);
+                }
+            }
+            Ok(())
         }
-        let mut traversal = vec![];
-        let mut next = 5;
-        loop {
-            traversal.push(next);
-            if next != 0 {
-                next = *path.get(&next).unwrap();
+
+        ///
+        /// Returns the priority at position `k`
+        /// (counting from 0) of the dequeue
+        /// order without changing the queue.
+        /// Small `k` are answered with a bounded
+        /// selection in O(n log k), larger ones
+        /// by sorting a copy of the heap.
+        /// 
+        pub fn kth_priority(&self, k: usize) -> Option<T> {
+            if k >= self.heap.len() {
+                return None;
+            }
+            let mut entries = if k <= 8 {
+                self.best_entries(k + 1)
             } else {
-                break;
+                self.sorted_entries()
+            };
+            Some(entries.swap_remove(k).0)
+        }
+
+        ///
+        /// Changes the priority of the data object
+        /// only if the new value would come out of
+        /// the queue strictly earlier. Returns true
+        /// if it was changed. Absent objects are
+        /// not inserted.
+        /// 
+        pub fn update_if_better(&mut self, value: T, data: &'a F) -> bool {
+            let index = match self.map.get(data) {
+                Some(i) => *i,
+                None => return false,
+            };
+            if !self.before(&(value.clone(), data), &self.heap[index]) {
+                return false;
             }
+            self.set_priority(index, value);
+            true
         }
-        traversal.reverse();
-        dbg!(traversal);
-    }
-}
\ No newline at end of file
+
+        ///
+        /// Returns the entries grouped by their
+        /// level in the heap tree. Level 0 holds
+        /// the root, level 1 its two children and
+        /// so on, each in heap index order.
+        /// 
+        pub fn level_order(&self) -> Vec<Vec<(T, &'a F)>> {
+            let mut levels = vec![];
+            let mut start = 0;
+            let mut width = 1;
+            while start < self.heap.len() {
+                let end = usize::min(start + width, self.heap.len());
+                levels.push(self.heap[start..end].to_vec());
+                start = end;
+                width *= 2;
+            }
+            levels
+        }
+
+        ///
+        /// Dequeues from the front until only
+        /// `target` elements are left and returns
+        /// the removed entries in dequeue order.
+        /// 
+        pub fn deq_until_size(&mut self, target: usize) -> Vec<(T, &'a F)> {
+            let mut removed = vec![];
+            while self.size() > target {
+                match self.deq() {
+                    Some(entry) => removed.push(entry),
+                    None => break,
+                }
+            }
+            removed
+        }
+
+        ///
+        /// Dequeues from the front for as long as
+        /// the front entry satisfies `pred` and
+        /// returns the removed entries in dequeue
+        /// order. The first entry that fails is
+        /// left in the queue.
+        /// 
+        pub fn deq_while<P>(&mut self, pred: P) -> Vec<(T, &'a F)>
+        where P: Fn(T, &'a F) -> bool
+        {
+            let mut removed = vec![];
+            while let Some((value, data)) = self.peek() {
+                if !pred(value, data) {
+                    break;
+                }
+                removed.push(self.remove_at(0));
+            }
+            removed
+        }
+
+        ///
+        /// Returns the `p` percentile of the
+        /// current priorities, by value from
+        /// smallest to largest, using the
+        /// nearest-rank method: the value at
+        /// rank `ceil(p * n)` (counting from 1).
+        /// `p` is clamped into `[0, 1]`.
+        /// 
+        pub fn percentile(&self, p: f64) -> Option<T>
+        where T: Ord
+        {
+            if self.heap.is_empty() {
+                return None;
+            }
+            let n = self.heap.len();
+            let rank = (p.clamp(0.0, 1.0) * n as f64).ceil() as usize;
+            let rank = rank.clamp(1, n);
+            let mut values: Vec<T> = self.heap.iter().map(|(v, _)| v.clone()).collect();
+            values.select_nth_unstable(rank - 1);
+            Some(values.swap_remove(rank - 1))
+        }
+
+        ///
+        /// Consumes both queues and returns one
+        /// holding all of their entries, built
+        /// with a single heapify, as `merge`
+        /// does. Panics under the same
+        /// conditions as `merge`.
+        /// 
+        pub fn merged(mut self, other: SortedQueue<'a, T, F, S>) -> SortedQueue<'a, T, F, S> {
+            self.merge(other);
+            self
+        }
+
+        ///
+        /// Moves every entry of `other` into this
+        /// queue and rebuilds the heap once. If
+        /// both hold the same reference, the
+        /// entry from `other` wins. Entries this
+        /// queue's enqueue guard refuses are
+        /// dropped. In stable mode the entries of
+        /// `other` are added in its insertion
+        /// order, after this queue's own. The
+        /// `enq_lazy` priorities of `other` come
+        /// along with its entries.
+        ///
+        /// Panics if only one queue has a custom
+        /// comparator, if two default queues
+        /// differ in orientation, if two custom
+        /// ones differ in whether their order is
+        /// reversed, or if the queues differ in
+        /// stable mode. Two custom comparators
+        /// cannot be told apart, so the merged
+        /// queue simply keeps this queue's one.
+        /// 
+        pub fn merge(&mut self, other: SortedQueue<'a, T, F, S>) {
+            assert_eq!(self.custom, other.custom, "cannot merge a custom comparator with the default one");
+            if self.custom {
+                assert_eq!(self.reversed, other.reversed, "cannot merge a reversed queue with an unreversed one");
+            } else {
+                assert_eq!(self.max, other.max, "cannot merge queues of different orientation");
+            }
+            assert_eq!(self.stable, other.stable, "cannot merge a stable queue with an unstable one");
+            let mut lazy = other.lazy;
+            let mut entries = other.heap;
+            if self.stable {
+                entries.sort_by_key(|(_, data)| other.seq.get(data).copied().unwrap_or(u64::MAX));
+            }
+            self.heap.reserve(entries.len());
+            for (value, data) in entries {
+                if self.guard_allows(data) {
+                    self.push_unsifted(value, data);
+                    match lazy.remove(data) {
+                        Some(f) => {
+                            self.lazy.insert(data, f);
+                        }
+                        None => {
+                            self.lazy.remove(data);
+                        }
+                    }
+                }
+            }
+            self.heapify();
+        }
+
+        ///
+        /// Sets a guard that every reference
+        /// must pass to be enqueued. `enq` skips
+        /// references it refuses, `enq_guarded`
+        /// reports them as an error.
+        /// 
+        pub fn set_enq_guard(&mut self, guard: impl Fn(&'a F) -> bool + 'static) {
+            self.enq_guard = Some(Rc::new(guard));
+        }
+
+        ///
+        /// Puts the value and reference in the
+        /// queue, or fails if the enqueue guard
+        /// refuses the reference.
+        /// 
+        pub fn enq_guarded(&mut self, value: T, data: &'a F)
+            -> Result<(), Box<dyn std::error::Error>> {
+            if !self.guard_allows(data) {
+                return Err(Box::new(RejectedByGuard));
+            }
+            self.enq(value, data);
+            Ok(())
+        }
+
+        ///
+        /// Consumes the queue and returns its
+        /// best entry, its worst entry and a
+        /// queue of everything else. With a
+        /// single element that element is the
+        /// best and there is no worst; an empty
+        /// queue gives neither.
+        /// 
+        pub fn take_extremes(mut self) -> Extremes<'a, T, F, S> {
+            let best = self.deq();
+            let worst = self.worst_index().map(|i| self.remove_at(i));
+            (best, worst, self)
+        }
+
+        ///
+        /// Puts the value and reference in the
+        /// queue. If the reference is already
+        /// there its priority is replaced instead
+        /// and the old priority is returned.
+        /// 
+        pub fn enq_replacing(&mut self, value: T, data: &'a F) -> Option<T> {
+            match self.map.get(data) {
+                Some(i) => Some(self.set_priority(*i, value)),
+                None => {
+                    self.enq(value, data);
+                    None
+                }
+            }
+        }
+
+        ///
+        /// Replaces every priority with the one
+        /// computed from its reference by `key`
+        /// and rebuilds the heap once.
+        /// 
+        pub fn recompute_priorities(&mut self, key: impl Fn(&'a F) -> T) {
+            for entry in self.heap.iter_mut() {
+                entry.0 = key(entry.1);
+            }
+            self.heapify();
+        }
+
+        ///
+        /// Dequeues up to `N` entries into a
+        /// fixed-size array without allocating.
+        /// Returns the array and how many slots
+        /// were filled; the rest are `None`.
+        /// 
+        pub fn pop_up_to<const N: usize>(&mut self) -> ([Option<(T, &'a F)>; N], usize) {
+            let count = usize::min(N, self.size());
+            let popped = std::array::from_fn(|_| self.deq());
+            (popped, count)
+        }
+
+        ///
+        /// Returns the priority shared by the
+        /// most entries. Ties go to the smallest
+        /// of the tied priorities.
+        /// 
+        pub fn mode(&self) -> Option<T>
+        where T: Ord + std::hash::Hash
+        {
+            let mut counts: HashMap<T, usize> = HashMap::new();
+            for (value, _) in self.heap.iter() {
+                *counts.entry(value.clone()).or_insert(0) += 1;
+            }
+            counts
+                .into_iter()
+                .max_by(|(v1, c1), (v2, c2)| c1.cmp(c2).then(v2.cmp(v1)))
+                .map(|(value, _)| value)
+        }
+
+        ///
+        /// Flips the queue between a max-queue and
+        /// a min-queue and rebuilds the heap in
+        /// place. NaN priorities still come out
+        /// last. A custom comparator is reversed
+        /// by swapping its arguments.
+        /// 
+        pub fn reverse_order(&mut self) {
+            self.max = !self.max;
+            if self.custom {
+                self.reversed = !self.reversed;
+            } else {
+                let max = self.max;
+                self.comp = Rc::new(move |x, y| comes_before(&x.0, &y.0, max));
+            }
+            self.heapify();
+        }
+
+        ///
+        /// Swaps in a new comparator without
+        /// rebuilding the heap. Priorities that
+        /// compare `Less` come out first. The
+        /// caller promises the new comparator
+        /// orders entries exactly like the old
+        /// one; debug builds check that the heap
+        /// is still valid and panic if it is not.
+        /// 
+        pub fn replace_comparator_no_reheapify(
+            &mut self,
+            comp: impl Fn(&T, &T) -> Ordering + 'static,
+        ) {
+            self.invalidate();
+            self.comp = Rc::new(move |x, y| comp(&x.0, &y.0) == Ordering::Less);
+            self.custom = true;
+            self.reversed = false;
+            debug_assert!(
+                self.diagnose().is_empty(),
+                "replacement comparator does not preserve the heap order"
+            );
+        }
+
+        ///
+        /// Returns how many times `enq` had to
+        /// grow the heap since the queue was made
+        /// or the counter was last reset.
+        /// 
+        pub fn growth_events(&self) -> usize {
+            self.growth_events
+        }
+
+        ///
+        /// Resets the `growth_events` counter.
+        /// 
+        pub fn reset_growth_events(&mut self) {
+            self.growth_events = 0;
+        }
+
+        ///
+        /// Removes and returns the entry at
+        /// position `n` (counting from 0) of the
+        /// dequeue order. The entry is found by
+        /// selection over a copy of the heap and
+        /// then removed in O(log n).
+        /// 
+        pub fn remove_at_rank(&mut self, n: usize) -> Option<(T, &'a F)> {
+            if n >= self.heap.len() {
+                return None;
+            }
+            let mut entries = self.heap.clone();
+            let (_, (_, data), _) = entries.select_nth_unstable_by(n, |a, b| self.order(a, b));
+            let index = self.map[*data];
+            Some(self.remove_at(index))
+        }
+
+        ///
+        /// Consumes the queue and splits it into
+        /// the entries that match `pred` and the
+        /// ones that do not. Both queues keep the
+        /// comparator, orientation, hasher, bound
+        /// and stable order of the original, and
+        /// each takes the `enq_lazy` priorities
+        /// of its own entries; other settings
+        /// like the soft limit or guard are not
+        /// carried over.
+        /// 
+        pub fn partition(mut self, pred: impl Fn(T, &'a F) -> bool)
+            -> (SortedQueue<'a, T, F, S>, SortedQueue<'a, T, F, S>)
+        where S: Clone
+        {
+            let mut matches = self.empty_like();
+            let mut rest = self.empty_like();
+            let (yes, no): (Vec<_>, Vec<_>) = std::mem::take(&mut self.heap)
+                .into_iter()
+                .partition(|(value, data)| pred(value.clone(), data));
+            for (_, data) in yes.iter() {
+                if let Some(f) = self.lazy.remove(data) {
+                    matches.lazy.insert(data, f);
+                }
+            }
+            rest.lazy = std::mem::take(&mut self.lazy);
+            matches.heap = yes;
+            matches.heapify();
+            rest.heap = no;
+            rest.heapify();
+            (matches, rest)
+        }
+
+        ///
+        /// Adds `delta` to every priority in O(n).
+        /// A shift keeps their order, so only a
+        /// stable queue is rebuilt. Uses `+`: an
+        /// integer overflow panics in debug builds.
+        /// 
+        pub fn offset_all(&mut self, delta: T)
+        where T: std::ops::Add<Output = T>
+        {
+            self.invalidate();
+            for entry in self.heap.iter_mut() {
+                entry.0 = entry.0.clone() + delta.clone();
+            }
+            if self.stable {
+                self.heapify();
+            }
+        }
+
+        ///
+        /// Multiplies every priority by `factor`
+        /// and rebuilds the heap. The product is
+        /// converted back through `From<f64>`, so
+        /// for integer-like priorities that impl
+        /// decides how the result is rounded.
+        /// 
+        pub fn decay(&mut self, factor: f64)
+        where T: Into<f64> + From<f64>
+        {
+            for entry in self.heap.iter_mut() {
+                entry.0 = T::from(entry.0.clone().into() * factor);
+            }
+            self.heapify();
+        }
+
+        ///
+        /// Returns true if the references in the
+        /// queue are exactly the ones given,
+        /// ignoring order and priorities.
+        /// 
+        pub fn contains_exactly(&self, refs: &[&'a F]) -> bool {
+            let wanted: HashSet<&'a F> = refs.iter().copied().collect();
+            wanted.len() == self.map.len()
+                && wanted.iter().all(|data| self.map.contains_key(*data))
+        }
+
+        ///
+        /// Puts the value and reference in the
+        /// queue and returns a handle for cheap
+        /// priority changes later on.
+        /// 
+        pub fn enq_handle(&mut self, value: T, data: &'a F) -> Handle<'a, F> {
+            self.enq(value, data);
+            let index = self.map.get(data).copied().unwrap_or(usize::MAX);
+            Handle { data, index: Cell::new(index) }
+        }
+
+        ///
+        /// Changes the priority of the entry the
+        /// handle points to. The cached index is
+        /// checked against the heap and only if
+        /// it is stale is the map consulted.
+        /// Fails if the entry has left the queue.
+        /// 
+        pub fn change_by_handle(&mut self, handle: &Handle<'a, F>, new_value: T)
+            -> Result<(), Box<dyn std::error::Error>> {
+            let cached = handle.index.get();
+            let index = if cached < self.heap.len() && self.heap[cached].1 == handle.data {
+                cached
+            } else {
+                match self.map.get(handle.data) {
+                    Some(i) => *i,
+                    None => return Err(Box::new(NotInQueue)),
+                }
+            };
+            let (_, new_index) = self.move_priority(index, new_value);
+            handle.index.set(new_index);
+            Ok(())
+        }
+
+        ///
+        /// Adds every reference and priority in
+        /// the map, heapifying once. References
+        /// already in the queue take the priority
+        /// from the map.
+        /// 
+        pub fn extend_from_map(&mut self, map: HashMap<&'a F, T>) {
+            self.load(map.into_iter().map(|(data, value)| (value, data)));
+        }
+
+        ///
+        /// Reserves room in the heap and the map
+        /// for every entry of `other`, ahead of
+        /// merging it into this queue.
+        /// 
+        pub fn reserve_for(&mut self, other: &SortedQueue<'a, T, F, S>) {
+            self.heap.reserve(other.size());
+            self.map.reserve(other.size());
+        }
+
+        ///
+        /// Dequeues the front like `deq` and
+        /// passes the removed reference to
+        /// `on_remove`, so the caller can keep
+        /// structures of their own in sync.
+        /// 
+        pub fn deq_notify(&mut self, mut on_remove: impl FnMut(&'a F)) -> Option<(T, &'a F)> {
+            let entry = self.deq()?;
+            on_remove(entry.1);
+            Some(entry)
+        }
+
+        ///
+        /// Adds a batch of `m` entries, picking
+        /// the cheaper of two ways: putting each
+        /// one in through `enq` in O(m log n), or
+        /// appending them all and heapifying once
+        /// in O(n + m). Either way the enqueue
+        /// guard and the bound apply, and
+        /// references already in the queue take
+        /// the new priority.
+        /// 
+        pub fn ingest_batch(&mut self, batch: impl IntoIterator<Item = (T, &'a F)>) {
+            let batch: Vec<(T, &'a F)> = batch.into_iter().collect();
+            let total = self.heap.len() + batch.len();
+            let depth = (usize::BITS - total.leading_zeros()) as usize;
+            if batch.len() * depth >= total {
+                self.load(batch);
+                return;
+            }
+            self.heap.reserve(batch.len());
+            for (value, data) in batch {
+                self.enq(value, data);
+            }
+        }
+
+        ///
+        /// Returns the entry with the largest
+        /// priority if `want_max` is true, or the
+        /// smallest otherwise. When that matches
+        /// the queue's orientation it is the root,
+        /// found in O(1); the other end is found
+        /// by scanning the leaves in O(n). A
+        /// custom comparator need not follow the
+        /// order of `T`, so then every entry is
+        /// scanned by value, skipping NaN.
+        /// 
+        pub fn extreme(&self, want_max: bool) -> Option<(T, &'a F)> {
+            if self.custom {
+                let mut end: Option<&(T, &'a F)> = None;
+                for entry in self.heap.iter() {
+                    if entry.0.partial_cmp(&entry.0).is_none() {
+                        continue;
+                    }
+                    match end {
+                        Some(e) if !comes_before(&entry.0, &e.0, want_max) => {}
+                        _ => end = Some(entry),
+                    }
+                }
+                return end.cloned();
+            }
+            if want_max == self.max {
+                return self.peek();
+            }
+            self.worst_index().map(|i| self.heap[i].clone())
+        }
+
+        ///
+        /// Puts the reference in the queue with
+        /// the priority `f` gives it, and keeps
+        /// `f` so `refresh` can compute it again
+        /// once the underlying data changes.
+        /// 
+        pub fn enq_lazy(&mut self, data: &'a F, f: impl Fn(&'a F) -> T + 'static) {
+            let value = f(data);
+            self.enq(value, data);
+            if self.map.contains_key(data) {
+                self.lazy.insert(data, Rc::new(f));
+            }
+        }
+
+        ///
+        /// Recomputes the priority of a reference
+        /// put in with `enq_lazy` and sifts it to
+        /// its new place. Fails if the reference
+        /// is not in the queue or was enqueued
+        /// some other way.
+        /// 
+        pub fn refresh(&mut self, data: &'a F) -> Result<(), Box<dyn std::error::Error>> {
+            let index = match self.map.get(data) {
+                Some(i) => *i,
+                None => return Err(Box::new(NotInQueue)),
+            };
+            let value = match self.lazy.get(data) {
+                Some(f) => f(data),
+                None => return Err(Box::new(NotInQueue)),
+            };
+            self.set_priority(index, value);
+            Ok(())
+        }
+
+        ///
+        /// Estimates the bytes allocated by the
+        /// queue: the heap's capacity in entries
+        /// plus the map's capacity in key and
+        /// index pairs with one control byte
+        /// each. It ignores allocator overhead
+        /// and spare map buckets, so treat it as
+        /// a rough figure, not an exact one.
+        /// 
+        pub fn memory_estimate(&self) -> usize {
+            let entry = std::mem::size_of::<(T, &'a F)>();
+            let slot = std::mem::size_of::<(&'a F, usize)>() + 1;
+            self.heap.capacity() * entry + self.map.capacity() * slot
+        }
+
+        ///
+        /// Removes the `k` best and the `k` worst
+        /// entries and returns them as two
+        /// batches, each ordered from its end of
+        /// the queue inward. If the queue holds
+        /// `2k` entries or fewer it is emptied;
+        /// the best batch is filled first.
+        /// 
+        pub fn trim(&mut self, k: usize) -> Trimmed<'a, T, F> {
+            let best = self.deq_until_size(self.size().saturating_sub(k));
+            let worst_count = usize::min(k, self.size());
+            let sorted = self.sorted_entries();
+            let mut worst = Vec::with_capacity(worst_count);
+            for (_, data) in sorted.iter().rev().take(worst_count) {
+                let index = self.map[*data];
+                worst.push(self.remove_at(index));
+            }
+            (best, worst)
+        }
+
+        ///
+        /// Keeps only the entries `pred` returns
+        /// true for and rebuilds the map and heap
+        /// once from the survivors.
+        /// 
+        pub fn retain<P>(&mut self, pred: P)
+        where P: Fn(T, &'a F) -> bool
+        {
+            self.heap.retain(|(value, data)| pred(value.clone(), data));
+            self.heapify();
+        }
+
+        ///
+        /// Calls `f` on every entry. Entries it
+        /// maps to `None` are removed, the others
+        /// take the returned priority, and the
+        /// heap is rebuilt once.
+        /// 
+        pub fn retain_map(&mut self, f: impl Fn(T, &'a F) -> Option<T>) {
+            self.heap.retain_mut(|entry| match f(entry.0.clone(), entry.1) {
+                Some(value) => {
+                    entry.0 = value;
+                    true
+                }
+                None => false,
+            });
+            self.heapify();
+        }
+
+        ///
+        /// Returns the address of the front
+        /// reference as an identity token, so a
+        /// caller can tell whether the front has
+        /// changed between two looks. The pointer
+        /// is only meant for comparing; it is
+        /// valid to read for as long as the data
+        /// itself lives, but nothing stops that
+        /// data from being dropped after `'a`.
+        /// 
+        pub fn front_ref_ptr(&self) -> Option<*const F> {
+            self.heap.first().map(|(_, data)| *data as *const F)
+        }
+
+        ///
+        /// Checks the queue with `diagnose` and
+        /// fixes whatever it finds. Heap entries
+        /// whose reference is missing from the map
+        /// are orphans and are dropped. When the
+        /// same reference sits in several heap
+        /// slots, the one the map points at (or
+        /// else the first) is kept and the rest
+        /// dropped. The map and heap are then
+        /// rebuilt. A healthy queue is left alone
+        /// and gets an empty report.
+        /// 
+        pub fn verify_and_repair(&mut self) -> RepairReport {
+            let problems = self.diagnose();
+            let mut report = RepairReport::default();
+            if problems.is_empty() {
+                return report;
+            }
+            for problem in problems {
+                match problem {
+                    InvariantViolation::HeapOrder { .. } => report.heap_reordered = true,
+                    _ => report.map_rebuilt = true,
+                }
+            }
+            let mut keep: HashMap<&'a F, usize> = HashMap::new();
+            for (index, (_, data)) in self.heap.iter().enumerate() {
+                if self.map.contains_key(data) {
+                    keep.entry(*data).or_insert(index);
+                }
+            }
+            for (data, slot) in self.map.iter() {
+                if *slot < self.heap.len() && self.heap[*slot].1 == *data {
+                    keep.insert(*data, *slot);
+                }
+            }
+            let mut index = 0;
+            self.heap.retain(|(_, data)| {
+                index += 1;
+                match keep.get(data) {
+                    Some(kept) if *kept == index - 1 => true,
+                    Some(_) => {
+                        report.entries_dropped += 1;
+                        false
+                    }
+                    None => {
+                        report.orphans_dropped += 1;
+                        false
+                    }
+                }
+            });
+            self.heapify();
+            report
+        }
+
+        ///
+        /// Consumes the queue into an iterator
+        /// that can be drained from the best end
+        /// and the worst end alike.
+        /// 
+        pub fn drain_sorted(self) -> DrainSorted<'a, T, F> {
+            DrainSorted { entries: self.sorted_entries().into_iter() }
+        }
+
+        ///
+        /// Returns an iterator that dequeues the
+        /// entries in priority order, leaving the
+        /// queue empty once it is used up.
+        /// 
+        pub fn drain(&mut self) -> SortedDrain<'_, 'a, T, F, S> {
+            SortedDrain { queue: self }
+        }
+
+        ///
+        /// Consumes the queue and returns its
+        /// entries in dequeue order, taking the
+        /// root off one at a time.
+        /// 
+        pub fn into_sorted_vec(mut self) -> Vec<(T, &'a F)> {
+            let mut sorted = Vec::with_capacity(self.size());
+            while let Some(entry) = self.deq() {
+                sorted.push(entry);
+            }
+            sorted
+        }
+
+        ///
+        /// Enqueues every pair. Into an empty
+        /// queue they are pushed and heapified
+        /// once in O(n); otherwise each one goes
+        /// through `enq`.
+        /// 
+        pub fn enq_all<I: IntoIterator<Item = (T, &'a F)>>(&mut self, iter: I) {
+            if self.heap.is_empty() {
+                self.load(iter);
+                return;
+            }
+            for (value, data) in iter {
+                self.enq(value, data);
+            }
+        }
+
+        ///
+        /// Swaps the front entry for a new one
+        /// with a single sift down and returns
+        /// the old front, which is cheaper than
+        /// `deq` followed by `enq`. On an empty
+        /// queue it enqueues like `enq` and
+        /// returns `None`. A reference already in
+        /// the queue falls back to `deq` and
+        /// `enq`; one the guard refuses is left
+        /// out and nothing changes.
+        /// 
+        pub fn replace_root(&mut self, value: T, data: &'a F) -> Option<(T, &'a F)> {
+            if !self.guard_allows(data) {
+                return None;
+            }
+            if self.heap.is_empty() || self.map.contains_key(data) {
+                let old = self.deq();
+                self.enq(value, data);
+                return old;
+            }
+            self.invalidate();
+            let old = std::mem::replace(&mut self.heap[0], (value, data));
+            self.map.remove(old.1);
+            if !self.lazy.is_empty() {
+                self.lazy.remove(old.1);
+            }
+            if self.stable {
+                self.seq.remove(old.1);
+            }
+            self.stamp(data);
+            self.map.insert(data, 0);
+            self.sift_down(0);
+            Some(old)
+        }
+
+        ///
+        /// Iterates over the entries in raw heap
+        /// order without changing the queue.
+        /// 
+        pub fn iter(&self) -> impl ExactSizeIterator<Item = (T, &'a F)> + '_ {
+            self.heap.iter().cloned()
+        }
+
+        ///
+        /// Returns true if any entry has a
+        /// priority between `lo` and `hi`,
+        /// inclusive. Stops at the first match.
+        /// 
+        pub fn any_in_range(&self, lo: T, hi: T) -> bool {
+            self.heap.iter().any(|(value, _)| lo <= *value && *value <= hi)
+        }
+
+        ///
+        /// Returns how many entries the heap and
+        /// the map can hold without reallocating.
+        /// 
+        pub fn capacity_report(&self) -> CapacityReport {
+            CapacityReport {
+                heap: self.heap.capacity(),
+                map: self.map.capacity(),
+            }
+        }
+
+        ///
+        /// Returns how many elements the queue
+        /// can hold before either the heap or the
+        /// map has to reallocate.
+        /// 
+        pub fn capacity(&self) -> usize {
+            usize::min(self.heap.capacity(), self.map.capacity())
+        }
+
+        ///
+        /// Reserves room for at least `additional`
+        /// more elements in the heap and the map.
+        /// 
+        pub fn reserve(&mut self, additional: usize) {
+            self.heap.reserve(additional);
+            self.map.reserve(additional);
+        }
+
+        ///
+        /// Shrinks the heap and the map as much as
+        /// possible.
+        /// 
+        pub fn shrink_to_fit(&mut self) {
+            self.heap.shrink_to_fit();
+            self.map.shrink_to_fit();
+        }
+
+        ///
+        /// Shrinks the map as much as possible
+        /// while leaving the heap's capacity
+        /// alone for reuse.
+        /// 
+        pub fn shrink_map_to_fit(&mut self) {
+            self.map.shrink_to_fit();
+        }
+
+        ///
+        /// Checks the heap order and the map
+        /// against the heap and returns every
+        /// problem found. An empty vector means
+        /// the queue is healthy.
+        /// 
+        pub fn diagnose(&self) -> Vec<InvariantViolation> {
+            let mut problems = vec![];
+            for child in 1..self.heap.len() {
+                let parent = (child - 1) / 2;
+                if self.before(&self.heap[child], &self.heap[parent]) {
+                    problems.push(InvariantViolation::HeapOrder { parent, child });
+                }
+            }
+            for (data, slot) in self.map.iter() {
+                if *slot >= self.heap.len() || self.heap[*slot].1 != *data {
+                    problems.push(InvariantViolation::MapMismatch { slot: *slot });
+                }
+            }
+            for (index, (_, data)) in self.heap.iter().enumerate() {
+                if self.map.get(*data) != Some(&index) {
+                    problems.push(InvariantViolation::OrphanedEntry { index });
+                }
+            }
+            problems
+        }
+
+        ///
+        /// Returns the entries in dequeue order.
+        /// The sorted view is built on first use
+        /// and kept until the queue is changed,
+        /// so repeated reads in between are O(1).
+        /// 
+        pub fn sorted_slice(&self) -> &[(T, &'a F)] {
+            self.sorted.get_or_init(|| {
+                #[cfg(test)]
+                self.sorted_builds.set(self.sorted_builds.get() + 1);
+                self.sorted_entries()
+            })
+        }
+
+        ///
+        /// Iterates over the entries from worst
+        /// to best by sorting a copy of the heap,
+        /// leaving the queue untouched.
+        /// 
+        pub fn sorted_iter_rev(&self) -> impl Iterator<Item = (T, &'a F)> + '_ {
+            self.sorted_entries().into_iter().rev()
+        }
+
+        ///
+        /// Returns the differences between each
+        /// pair of neighbouring priorities in
+        /// dequeue order, always taken as the
+        /// larger minus the smaller so they are
+        /// never negative for either orientation.
+        /// 
+        pub fn sorted_gaps(&self) -> Vec<T>
+        where T: std::ops::Sub<Output = T>
+        {
+            self.sorted_entries()
+                .windows(2)
+                .map(|pair| {
+                    let (a, b) = (pair[0].0.clone(), pair[1].0.clone());
+                    if a < b { b - a } else { a - b }
+                })
+                .collect()
+        }
+
+        ///
+        /// Drains the queue and panics with a
+        /// line-by-line diff if the entries do
+        /// not come out exactly as `expected`.
+        /// Available with the `test-util` feature.
+        /// 
+        #[cfg(any(test, feature = "test-util"))]
+        pub fn assert_drains_to(mut self, expected: &[(T, &'a F)])
+        where T: std::fmt::Debug,
+              F: std::fmt::Debug
+        {
+            let mut actual = vec![];
+            while let Some(entry) = self.deq() {
+                actual.push(entry);
+            }
+            if actual == expected {
+                return;
+            }
+            let mut message = String::from("queue did not drain as expected:\n");
+            for i in 0..usize::max(actual.len(), expected.len()) {
+                match (expected.get(i), actual.get(i)) {
+                    (Some(e), Some(a)) if e == a => {
+                        message.push_str(&format!("    {}: {:?}\n", i, a));
+                    }
+                    (e, a) => {
+                        if let Some(e) = e {
+                            message.push_str(&format!("  - {}: {:?}\n", i, e));
+                        }
+                        if let Some(a) = a {
+                            message.push_str(&format!("  + {}: {:?}\n", i, a));
+                        }
+                    }
+                }
+            }
+            panic!("{}", message);
+        }
+
+        ///
+        /// Checks the heap order and that the map
+        /// and heap agree, returning a description
+        /// of the first problem found: heap order
+        /// first, then the map, each by lowest
+        /// index. Available with the `validate`
+        /// feature.
+        /// 
+        #[cfg(any(test, feature = "validate"))]
+        pub fn check_invariants(&self) -> Result<(), String> {
+            let first = self.diagnose().into_iter().min_by_key(|problem| match problem {
+                InvariantViolation::HeapOrder { child, .. } => (0, *child),
+                InvariantViolation::MapMismatch { slot } => (1, *slot),
+                InvariantViolation::OrphanedEntry { index } => (2, *index),
+            });
+            match first {
+                Some(problem) => Err(problem.to_string()),
+                None => Ok(()),
+            }
+        }
+
+        #[cfg(test)]
+        pub(crate) fn sorted_builds(&self) -> usize {
+            self.sorted_builds.get()
+        }
+
+        #[cfg(test)]
+        pub(crate) fn heap_mut(&mut self) -> &mut Vec<(T, &'a F)> {
+            self.invalidate();
+            &mut self.heap
+        }
+
+        #[cfg(test)]
+        pub(crate) fn map_mut(&mut self) -> &mut HashMap<&'a F, usize, S> {
+            &mut self.map
+        }
+
+        #[cfg(test)]
+        pub(crate) fn set_next_seq(&mut self, next: u64) {
+            self.next_seq = next;
+        }
+    }
+
+    ///
+    /// Turns a comparator into an empty queue
+    /// ordered by it. Priorities that compare
+    /// `Less` come out first.
+    /// 
+    impl<'a, T, F, C> From<C> for SortedQueue<'a, T, F>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash,
+          C: Fn(&T, &T) -> Ordering + 'static
+    {
+        fn from(comp: C) -> Self {
+            let mut queue = SortedQueue::new(false);
+            queue.comp = Rc::new(move |x, y| comp(&x.0, &y.0) == Ordering::Less);
+            queue.custom = true;
+            queue
+        }
+    }
+
+    ///
+    /// Builds a min-queue from a vector of
+    /// pairs in O(n), failing if the same
+    /// reference appears more than once.
+    /// 
+    impl<'a, T, F> TryFrom<Vec<(T, &'a F)>> for SortedQueue<'a, T, F>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash
+    {
+        type Error = DuplicateKey;
+
+        fn try_from(pairs: Vec<(T, &'a F)>) -> Result<Self, Self::Error> {
+            let mut queue = SortedQueue::new(false);
+            queue.heap = pairs;
+            queue.heapify();
+            if queue.map.len() != queue.heap.len() {
+                return Err(DuplicateKey);
+            }
+            Ok(queue)
+        }
+    }
+
+    ///
+    /// Builds a min-queue from a slice of
+    /// pairs, see the `Vec` conversion.
+    /// 
+    impl<'a, T, F> TryFrom<&[(T, &'a F)]> for SortedQueue<'a, T, F>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash
+    {
+        type Error = DuplicateKey;
+
+        fn try_from(pairs: &[(T, &'a F)]) -> Result<Self, Self::Error> {
+            SortedQueue::try_from(pairs.to_vec())
+        }
+    }
+
+    ///
+    /// Adds the pairs through `enq_all`.
+    /// 
+    impl<'a, T, F, S> Extend<(T, &'a F)> for SortedQueue<'a, T, F, S>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash,
+          S: BuildHasher
+    {
+        fn extend<I: IntoIterator<Item = (T, &'a F)>>(&mut self, iter: I) {
+            self.enq_all(iter);
+        }
+    }
+
+    ///
+    /// Clones the entries and settings into
+    /// an independent queue. The comparator,
+    /// guard and lazy priority closures are
+    /// shared between the two, which is safe
+    /// since they are never changed in place.
+    /// 
+    impl<'a, T, F, S> Clone for SortedQueue<'a, T, F, S>
+    where T: PartialOrd + Clone,
+          F: Eq + std::hash::Hash,
+          S: Clone
+    {
+        fn clone(&self) -> Self {
+            SortedQueue {
+                heap: self.heap.clone(),
+                comp: Rc::clone(&self.comp),
+                map: self.map.clone(),
+                max: self.max,
+                soft_limit: self.soft_limit,
+                enq_guard: self.enq_guard.clone(),
+                growth_events: self.growth_events,
+                sorted: self.sorted.clone(),
+                #[cfg(test)]
+                sorted_builds: self.sorted_builds.clone(),
+                lazy: self.lazy.clone(),
+                bound: self.bound,
+                custom: self.custom,
+                reversed: self.reversed,
+                stable: self.stable,
+                seq: self.seq.clone(),
+                next_seq: self.next_seq,
+            }
+        }
+    }
+
+    ///
+    /// Shows the orientation, the size and
+    /// the heap entries in raw heap order.
+    /// 
+    impl<'a, T, F, S> std::fmt::Debug for SortedQueue<'a, T, F, S>
+    where T: PartialOrd + Clone + std::fmt::Debug,
+          F: Eq + std::hash::Hash + std::fmt::Debug,
+          S: BuildHasher
+    {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("SortedQueue")
+                .field("max", &self.max)
+                .field("size", &self.size())
+                .field("heap", &self.heap)
+                .finish()
+        }
+    }
+
+    ///
+    /// Writes the heap entries, the
+    /// orientation, whether the queue is
+    /// stable and its bound; the map is not
+    /// stored. A stable queue writes its
+    /// entries in dequeue order so ties can be
+    /// put back in line.
+    /// A queue with a custom comparator cannot
+    /// be written, since the closure cannot be
+    /// stored, and fails with an error. The
+    /// keys are borrowed, so there is no
+    /// matching `Deserialize`: read the data
+    /// back as an `OwnedSortedQueue` instead,
+    /// which has the same layout.
+    /// 
+    #[cfg(feature = "serde")]
+    impl<'a, T, F, S> serde::Serialize for SortedQueue<'a, T, F, S>
+    where T: PartialOrd + Clone + serde::Serialize,
+          F: Eq + std::hash::Hash + serde::Serialize,
+          S: BuildHasher
+    {
+        fn serialize<Z: serde::Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+            use serde::ser::{Error, SerializeStruct};
+            if self.custom {
+                return Err(Z::Error::custom("cannot serialize a queue with a custom comparator"));
+            }
+            let mut state = serializer.serialize_struct("SortedQueue", 4)?;
+            if self.stable {
+                state.serialize_field("heap", &self.sorted_entries())?;
+            } else {
+                state.serialize_field("heap", &self.heap)?;
+            }
+            state.serialize_field("max", &self.max)?;
+            state.serialize_field("stable", &self.stable)?;
+            state.serialize_field("bound", &self.bound)?;
+            state.end()
+        }
+    }
+}
+
+///
+/// Module containing a sorted queue that
+/// owns its keys, for callers who cannot
+/// keep every key alive outside of the
+/// queue and can afford to clone them.
+/// 
+pub mod owned_queue {
+    use std::collections::HashMap;
+    use std::hash::Hash;
+    use crate::sorted_queue::{comes_before, next_stamp, NotInQueue};
+
+    ///
+    /// A sorted priority queue like
+    /// `SortedQueue`, but the heap stores
+    /// `(T, K)` pairs with owned keys instead
+    /// of references. A queue read back from
+    /// a stable or bounded `SortedQueue` keeps
+    /// breaking ties by insertion order and
+    /// holding at most its bound.
+    /// 
+    pub struct OwnedSortedQueue<T, K>
+    where T: PartialOrd + Clone,
+          K: Eq + Hash + Clone
+    {
+        heap: Vec<(T, K)>,
+        map: HashMap<K, usize>,
+        max: bool,
+        bound: Option<usize>,
+        stable: bool,
+        seq: HashMap<K, u64>,
+        next_seq: u64,
+    }
+
+    impl<T, K> OwnedSortedQueue<T, K>
+    where T: PartialOrd + Clone,
+          K: Eq + Hash + Clone
+    {
+        ///
+        /// Returns a new, blank priority queue.
+        /// 
+        pub fn new(max: bool) -> OwnedSortedQueue<T, K> {
+            OwnedSortedQueue {
+                heap: vec![],
+                map: HashMap::new(),
+                max,
+                bound: None,
+                stable: false,
+                seq: HashMap::new(),
+                next_seq: 0,
+            }
+        }
+
+        fn before(&self, a: usize, b: usize) -> bool {
+            self.entry_before(&self.heap[a], &self.heap[b])
+        }
+
+        fn entry_before(&self, a: &(T, K), b: &(T, K)) -> bool {
+            if comes_before(&a.0, &b.0, self.max) {
+                return true;
+            }
+            if !self.stable || comes_before(&b.0, &a.0, self.max) {
+                return false;
+            }
+            let stamp = |key: &K| self.seq.get(key).copied().unwrap_or(u64::MAX);
+            stamp(&a.1) < stamp(&b.1)
+        }
+
+        fn push(&mut self, value: T, key: K) -> usize {
+            if self.stable {
+                let stamp = next_stamp(&mut self.seq, &mut self.next_seq);
+                self.seq.insert(key.clone(), stamp);
+            }
+            let new_index = self.heap.len();
+            self.map.insert(key.clone(), new_index);
+            self.heap.push((value, key));
+            new_index
+        }
+
+        fn remove_at(&mut self, index: usize) -> (T, K) {
+            let last = self.heap.len() - 1;
+            self.swap(index, last);
+            let (value, key) = self.heap.pop().unwrap();
+            self.map.remove(&key);
+            self.seq.remove(&key);
+            if index < self.heap.len() {
+                self.sift_down(index);
+                self.sift_up(index);
+            }
+            (value, key)
+        }
+
+        fn worst_index(&self) -> Option<usize> {
+            if self.heap.is_empty() {
+                return None;
+            }
+            let mut worst = self.heap.len() / 2;
+            for i in worst + 1..self.heap.len() {
+                if self.before(worst, i) {
+                    worst = i;
+                }
+            }
+            Some(worst)
+        }
+
+        fn swap(&mut self, i1: usize, i2: usize) {
+            self.heap.swap(i1, i2);
+            for i in [i1, i2] {
+                if let Some(slot) = self.map.get_mut(&self.heap[i].1) {
+                    *slot = i;
+                }
+            }
+        }
+
+        fn sift_down(&mut self, index: usize) {
+            let mut index = index;
+            loop {
+                let mut next = index;
+                for child in [2 * index + 1, 2 * index + 2] {
+                    if child < self.heap.len() && self.before(child, next) {
+                        next = child;
+                    }
+                }
+                if next == index {
+                    break;
+                }
+                self.swap(index, next);
+                index = next;
+            }
+        }
+
+        fn sift_up(&mut self, index: usize) {
+            let mut index = index;
+            while index > 0 {
+                let parent = (index - 1) / 2;
+                if !self.before(index, parent) {
+                    break;
+                }
+                self.swap(index, parent);
+                index = parent;
+            }
+        }
+
+        fn set_priority(&mut self, index: usize, new_value: T) {
+            self.heap[index].0 = new_value;
+            self.sift_down(index);
+            self.sift_up(index);
+        }
+
+        ///
+        /// Puts the value and key in the queue.
+        /// If the key is already there its
+        /// priority is replaced instead. A full
+        /// bounded queue drops its worst entry
+        /// for a better one, or else the new one.
+        /// 
+        pub fn enq(&mut self, value: T, key: K) {
+            if let Some(index) = self.map.get(&key) {
+                self.set_priority(*index, value);
+                return;
+            }
+            if let Some(bound) = self.bound {
+                if self.heap.len() >= bound {
+                    let entry = (value, key);
+                    let worst = match self.worst_index() {
+                        Some(worst) if self.entry_before(&entry, &self.heap[worst]) => worst,
+                        _ => return,
+                    };
+                    self.remove_at(worst);
+                    let new_index = self.push(entry.0, entry.1);
+                    self.sift_up(new_index);
+                    return;
+                }
+            }
+            let new_index = self.push(value, key);
+            self.sift_up(new_index);
+        }
+
+        ///
+        /// Enqueues every pair, reserving room
+        /// from the iterator's size hint. New
+        /// keys are pushed and keys already in
+        /// the queue take the new priority, then
+        /// the heap is rebuilt once in O(n). A
+        /// bounded queue puts them in one by one
+        /// through `enq` instead.
+        /// 
+        pub fn enq_all<I: IntoIterator<Item = (T, K)>>(&mut self, iter: I) {
+            let iter = iter.into_iter();
+            let (lower, _) = iter.size_hint();
+            self.heap.reserve(lower);
+            self.map.reserve(lower);
+            if self.bound.is_some() {
+                for (value, key) in iter {
+                    self.enq(value, key);
+                }
+                return;
+            }
+            for (value, key) in iter {
+                match self.map.get(&key) {
+                    Some(index) => self.heap[*index].0 = value,
+                    None => {
+                        self.push(value, key);
+                    }
+                }
+            }
+            for index in (0..self.heap.len() / 2).rev() {
+                self.sift_down(index);
+            }
+        }
+
+        ///
+        /// Removes the value from the front
+        /// of the queue and returns the value
+        /// and its key.
+        /// 
+        pub fn deq(&mut self) -> Option<(T, K)> {
+            if self.heap.is_empty() {
+                return None;
+            }
+            Some(self.remove_at(0))
+        }
+
+        ///
+        /// Returns the value and key at the
+        /// front of the queue without removing
+        /// them.
+        /// 
+        pub fn peek(&self) -> Option<(T, K)> {
+            self.heap.first().cloned()
+        }
+
+        ///
+        /// If this key is found in the heap,
+        /// then its weight is returned.
+        /// 
+        pub fn get_weight(&self, key: &K) -> Option<T> {
+            let index = self.map.get(key)?;
+            Some(self.heap[*index].0.clone())
+        }
+
+        ///
+        /// Tries to find the key in the queue and
+        /// change its priority value, fails if it
+        /// cannot be found in the queue.
+        /// 
+        pub fn change_priority(&mut self, new_value: T, key: &K)
+            -> Result<(), Box<dyn std::error::Error>> {
+            let index = match self.map.get(key) {
+                Some(i) => *i,
+                None => return Err(Box::new(NotInQueue)),
+            };
+            self.set_priority(index, new_value);
+            Ok(())
+        }
+
+        ///
+        /// Returns the number of elements
+        /// in the priority queue.
+        /// 
+        pub fn size(&self) -> usize {
+            self.heap.len()
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(serde::Deserialize)]
+    struct Stored<T, K> {
+        heap: Vec<(T, K)>,
+        max: bool,
+        #[serde(default)]
+        stable: bool,
+        #[serde(default)]
+        bound: Option<usize>,
+    }
+
+    ///
+    /// Writes the heap entries, the
+    /// orientation, whether the queue is
+    /// stable and its bound, in the same
+    /// layout as `SortedQueue`.
+    /// 
+    #[cfg(feature = "serde")]
+    impl<T, K> serde::Serialize for OwnedSortedQueue<T, K>
+    where T: PartialOrd + Clone + serde::Serialize,
+          K: Eq + Hash + Clone + serde::Serialize
+    {
+        fn serialize<Z: serde::Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+            use serde::ser::SerializeStruct;
+            let mut state = serializer.serialize_struct("SortedQueue", 4)?;
+            if self.stable {
+                let mut entries = self.heap.clone();
+                entries.sort_by(|a, b| match (self.entry_before(a, b), self.entry_before(b, a)) {
+                    (true, _) => std::cmp::Ordering::Less,
+                    (_, true) => std::cmp::Ordering::Greater,
+                    _ => std::cmp::Ordering::Equal,
+                });
+                state.serialize_field("heap", &entries)?;
+            } else {
+                state.serialize_field("heap", &self.heap)?;
+            }
+            state.serialize_field("max", &self.max)?;
+            state.serialize_field("stable", &self.stable)?;
+            state.serialize_field("bound", &self.bound)?;
+            state.end()
+        }
+    }
+
+    ///
+    /// Reads back the entries and the settings
+    /// and enqueues the entries again,
+    /// rebuilding the map, so stored data that
+    /// is out of heap order still gives a
+    /// valid queue. A stable queue stamps the
+    /// entries in the order they were written,
+    /// which puts ties back in line.
+    /// 
+    #[cfg(feature = "serde")]
+    impl<'de, T, K> serde::Deserialize<'de> for OwnedSortedQueue<T, K>
+    where T: PartialOrd + Clone + serde::Deserialize<'de>,
+          K: Eq + Hash + Clone + serde::Deserialize<'de>
+    {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let stored = Stored::<T, K>::deserialize(deserializer)?;
+            let mut queue = OwnedSortedQueue::new(stored.max);
+            queue.stable = stored.stable;
+            queue.bound = stored.bound;
+            queue.enq_all(stored.heap);
+            Ok(queue)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sorted_queue::*;
+    use super::owned_queue::OwnedSortedQueue;
+
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn compare_tuple() {
+        let x = (4, "hello");
+        let y = (4, "there");
+        println!("{}", x > y);
+    }
+
+    #[derive(Hash, PartialEq, Eq, Debug)]
+    struct Employee<'a> {
+        name: &'a str,
+        id: u64,
+    }
+
+    #[test]
+    fn simple_queue() {
+        let emp_list = [
+            Employee { name: "victor", id: 10237 },
+            Employee { name: "hugo", id: 2937 },
+            Employee { name: "marcus", id: 1902 },
+        ];
+        let mut queue = SortedQueue::new(true);
+        for (i, emp) in emp_list.iter().enumerate() {
+            queue.enq(i, emp);
+        }
+        for _ in 0..3 {
+            let (_, emp) = queue.deq().unwrap();
+            dbg!(emp);
+        }
+    }
+
+    #[derive(PartialEq, Eq, Hash)]
+    struct Node {
+        weight: i32,
+        index: usize,
+    }
+
+    #[test]
+    fn graph_queue() {
+        let adjacency_list: Vec<Vec<(usize, i32)>> = vec![
+            vec![(1, 4), (2, 3)],
+            vec![(2, 5), (3, 2)],
+            vec![(3, 7)],
+            vec![(4, 2)],
+            vec![(0, 4), (1, 4), (5, 6)],
+            vec![],
+        ];
+        let mut nodes = vec![];
+        nodes.push(Node {weight: 0, index: 0});
+        for i in 1..adjacency_list.len() {
+            nodes.push(Node {weight: i32::MAX, index: i});
+        }
+        let mut queue: SortedQueue<i32, Node> = SortedQueue::new(false);
+        for node in nodes.iter().skip(1) {
+            queue.enq(i32::MAX, node);
+        }
+        let mut weights = [i32::MAX; 6];
+        weights[0] = 0;
+        let mut path = HashMap::new();
+        path.insert(0, 0);
+        let (mut cur_weight, mut cur_node) = (0, &nodes[0]);
+        loop {
+            for adj in adjacency_list[cur_node.index].iter() {
+                let (index, weight) = adj;
+                let total_weight = weight + cur_weight;
+                if total_weight < weights[*index] {
+                    queue.change_priority(total_weight, &nodes[*index]).unwrap();
+                    weights[*index] = total_weight;
+                    path.insert(*index, cur_node.index);
+                }
+            }
+            if queue.size() == 0 {
+                break;
+            }
+            let cur = queue.deq().unwrap();
+            cur_weight = cur.0;
+            cur_node = cur.1;
+        }
+        let mut traversal = vec![];
+        let mut next = 5;
+        loop {
+            traversal.push(next);
+            if next != 0 {
+                next = *path.get(&next).unwrap();
+            } else {
+                break;
+            }
+        }
+        traversal.reverse();
+        dbg!(traversal);
+    }
+
+    #[test]
+    fn soft_limit() {
+        let emp_list = [
+            Employee { name: "victor", id: 10237 },
+            Employee { name: "hugo", id: 2937 },
+            Employee { name: "marcus", id: 1902 },
+        ];
+        let mut queue = SortedQueue::new(true);
+        assert!(!queue.over_limit());
+        queue.set_soft_limit(2);
+        assert!(queue.enq_checked(1, &emp_list[0]).is_ok());
+        assert!(queue.enq_checked(2, &emp_list[1]).is_ok());
+        assert!(!queue.over_limit());
+        assert!(queue.enq_checked(3, &emp_list[2]).is_err());
+        assert!(queue.over_limit());
+        assert_eq!(queue.size(), 3);
+        queue.deq();
+        assert!(!queue.over_limit());
+    }
+
+    #[test]
+    fn dedup_priorities() {
+        let ids: Vec<u64> = (0..8).collect();
+        let priorities = [3, 1, 3, 2, 1, 3, 5, 2];
+        let mut queue = SortedQueue::new(false);
+        for (p, id) in priorities.iter().zip(ids.iter()) {
+            queue.enq(*p, id);
+        }
+        let removed = queue.dedup_by_priority();
+        assert_eq!(removed.len(), 4);
+        assert_eq!(queue.size(), 4);
+        for (p, id) in removed {
+            assert_eq!(queue.get_weight(id), None);
+            assert_eq!(priorities[*id as usize], p);
+        }
+        let mut drained = vec![];
+        while let Some((p, id)) = queue.deq() {
+            assert_eq!(priorities[*id as usize], p);
+            drained.push(p);
+        }
+        assert_eq!(drained, vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn reuse_heap_vec() {
+        let ids: Vec<u64> = (0..10).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        let capacity = queue.capacity_report().heap;
+        let mut heap = queue.into_vec_and_reuse();
+        assert_eq!(heap.capacity(), capacity);
+        assert_eq!(heap.len(), 10);
+        heap.sort();
+        for (i, (p, id)) in heap.into_iter().enumerate() {
+            assert_eq!(p, i as u64);
+            assert_eq!(*id, i as u64);
+        }
+    }
+
+    #[test]
+    fn remap_many_refs() {
+        let old_ids: Vec<u64> = (0..4).collect();
+        let new_ids: Vec<u64> = (10..14).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in old_ids.iter() {
+            queue.enq(*id, id);
+        }
+        let mut mapping = HashMap::new();
+        for i in 0..3 {
+            mapping.insert(&old_ids[i], &new_ids[i]);
+        }
+        queue.remap_refs(&mapping).unwrap();
+        for i in 0..3 {
+            assert_eq!(queue.get_weight(&old_ids[i]), None);
+            assert_eq!(queue.get_weight(&new_ids[i]), Some(i as u64));
+        }
+        assert_eq!(queue.get_weight(&old_ids[3]), Some(3));
+
+        let mut collision = HashMap::new();
+        collision.insert(&new_ids[0], &old_ids[3]);
+        assert!(queue.remap_refs(&collision).is_err());
+        assert_eq!(queue.get_weight(&new_ids[0]), Some(0));
+        let (p, id) = queue.deq().unwrap();
+        assert_eq!((p, *id), (0, 10));
+    }
+
+    #[test]
+    fn kth_best_priority() {
+        let priorities = [42, 7, 19, 3, 88, 23, 7, 61, 5, 14, 30, 2];
+        let ids: Vec<usize> = (0..priorities.len()).collect();
+        let mut min_queue = SortedQueue::new(false);
+        let mut max_queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            min_queue.enq(priorities[*id], id);
+            max_queue.enq(priorities[*id], id);
+        }
+        let mut sorted = priorities.to_vec();
+        sorted.sort();
+        assert_eq!(min_queue.kth_priority(2), Some(sorted[2]));
+        assert_eq!(max_queue.kth_priority(2), Some(sorted[sorted.len() - 3]));
+        for (k, p) in sorted.iter().enumerate() {
+            assert_eq!(min_queue.kth_priority(k), Some(*p));
+        }
+        assert_eq!(min_queue.kth_priority(sorted.len()), None);
+        assert_eq!(min_queue.size(), priorities.len());
+    }
+
+    #[test]
+    fn try_from_pairs() {
+        let ids: Vec<u64> = (0..6).collect();
+        let pairs: Vec<(u64, &u64)> = ids.iter().rev().map(|id| (*id, id)).collect();
+        let mut queue = SortedQueue::try_from(pairs.as_slice()).unwrap();
+        assert_eq!(queue.size(), 6);
+        for id in ids.iter() {
+            assert_eq!(queue.deq(), Some((*id, id)));
+        }
+
+        let duplicate = vec![(1, &ids[0]), (2, &ids[1]), (3, &ids[0])];
+        assert!(SortedQueue::try_from(duplicate).is_err());
+    }
+
+    #[test]
+    fn update_only_if_better() {
+        let ids: Vec<u64> = (0..4).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter().take(3) {
+            queue.enq(*id * 10, id);
+        }
+        assert!(queue.update_if_better(5, &ids[2]));
+        assert_eq!(queue.get_weight(&ids[2]), Some(5));
+        assert!(!queue.update_if_better(15, &ids[2]));
+        assert!(!queue.update_if_better(5, &ids[2]));
+        assert_eq!(queue.get_weight(&ids[2]), Some(5));
+        assert!(!queue.update_if_better(0, &ids[3]));
+        assert_eq!(queue.size(), 3);
+        assert_eq!(queue.deq(), Some((0, &ids[0])));
+        assert_eq!(queue.deq(), Some((5, &ids[2])));
+        assert_eq!(queue.deq(), Some((10, &ids[1])));
+    }
+
+    #[test]
+    fn heap_levels() {
+        let ids: Vec<u64> = (0..7).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        let levels = queue.level_order();
+        let sizes: Vec<usize> = levels.iter().map(|l| l.len()).collect();
+        assert_eq!(sizes, vec![1, 2, 4]);
+        assert_eq!(levels[0][0], (0, &ids[0]));
+        assert_eq!(levels[1], vec![(1, &ids[1]), (2, &ids[2])]);
+    }
+
+    #[test]
+    fn drain_to_size() {
+        let ids: Vec<u64> = (0..10).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        let removed = queue.deq_until_size(4);
+        let priorities: Vec<u64> = removed.iter().map(|(p, _)| *p).collect();
+        assert_eq!(priorities, vec![9, 8, 7, 6, 5, 4]);
+        assert_eq!(queue.size(), 4);
+        assert!(queue.deq_until_size(20).is_empty());
+        assert_eq!(queue.size(), 4);
+        assert_eq!(queue.deq(), Some((3, &ids[3])));
+    }
+
+    #[test]
+    fn custom_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let ids: Vec<u64> = (0..20).collect();
+        let hasher = BuildHasherDefault::<DefaultHasher>::default();
+        let mut queue = SortedQueue::with_hasher(false, hasher);
+        for id in ids.iter().rev() {
+            queue.enq(*id, id);
+        }
+        queue.change_priority(100, &ids[0]).unwrap();
+        assert_eq!(queue.get_weight(&ids[0]), Some(100));
+        let (first, _) = queue.deq().unwrap();
+        assert_eq!(first, 1);
+        assert_eq!(queue.size(), 19);
+    }
+
+    #[test]
+    fn priority_percentiles() {
+        let ids: Vec<u64> = (1..=100).collect();
+        let mut queue = SortedQueue::new(true);
+        assert_eq!(queue.percentile(0.5), None);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        assert_eq!(queue.percentile(0.5), Some(50));
+        assert_eq!(queue.percentile(0.95), Some(95));
+        assert_eq!(queue.percentile(0.999), Some(100));
+        assert_eq!(queue.percentile(-1.0), Some(1));
+        assert_eq!(queue.percentile(2.0), Some(100));
+        assert_eq!(queue.size(), 100);
+    }
+
+    #[test]
+    fn merged_queues() {
+        let ids: Vec<u64> = (0..8).collect();
+        let mut first = SortedQueue::new(false);
+        let mut second = SortedQueue::new(false);
+        for id in ids.iter().take(5) {
+            first.enq(*id * 2, id);
+        }
+        for id in ids.iter().skip(4) {
+            second.enq(*id * 2 + 1, id);
+        }
+        let mut queue = first.merged(second);
+        assert_eq!(queue.size(), 8);
+        assert_eq!(queue.get_weight(&ids[4]), Some(9));
+        let mut drained = vec![];
+        while let Some((p, _)) = queue.deq() {
+            drained.push(p);
+        }
+        assert_eq!(drained, vec![0, 2, 4, 6, 9, 11, 13, 15]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot merge queues of different orientation")]
+    fn merged_orientation_mismatch() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut first = SortedQueue::new(false);
+        let mut second = SortedQueue::new(true);
+        first.enq(0, &ids[0]);
+        second.enq(1, &ids[1]);
+        first.merged(second);
+    }
+
+    #[test]
+    fn diagnose_violations() {
+        let ids: Vec<u64> = (0..5).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        assert!(queue.diagnose().is_empty());
+
+        queue.heap_mut()[4].0 = 0;
+        assert_eq!(
+            queue.diagnose(),
+            vec![InvariantViolation::HeapOrder { parent: 1, child: 4 }]
+        );
+        queue.heap_mut()[4].0 = 4;
+
+        queue.map_mut().insert(&ids[3], 2);
+        let problems = queue.diagnose();
+        assert!(problems.contains(&InvariantViolation::MapMismatch { slot: 2 }));
+        assert!(problems.contains(&InvariantViolation::OrphanedEntry { index: 3 }));
+        queue.map_mut().insert(&ids[3], 3);
+
+        queue.map_mut().remove(&ids[1]);
+        assert_eq!(
+            queue.diagnose(),
+            vec![InvariantViolation::OrphanedEntry { index: 1 }]
+        );
+    }
+
+    #[test]
+    fn enq_guard() {
+        let emp_list = [
+            Employee { name: "victor", id: 10237 },
+            Employee { name: "nobody", id: 0 },
+            Employee { name: "marcus", id: 1902 },
+        ];
+        let mut queue = SortedQueue::new(true);
+        queue.set_enq_guard(|emp: &Employee| emp.id != 0);
+        assert!(queue.enq_guarded(1, &emp_list[0]).is_ok());
+        assert!(queue.enq_guarded(2, &emp_list[1]).is_err());
+        queue.enq(3, &emp_list[1]);
+        queue.enq(4, &emp_list[2]);
+        assert_eq!(queue.size(), 2);
+        assert_eq!(queue.get_weight(&emp_list[1]), None);
+        assert_eq!(queue.deq(), Some((4, &emp_list[2])));
+    }
+
+    #[test]
+    fn take_both_extremes() {
+        let ids: Vec<u64> = (0..5).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in [3, 0, 4, 1, 2] {
+            queue.enq(id * 10, &ids[id as usize]);
+        }
+        let (best, worst, mut rest) = queue.take_extremes();
+        assert_eq!(best, Some((0, &ids[0])));
+        assert_eq!(worst, Some((40, &ids[4])));
+        assert_eq!(rest.size(), 3);
+        assert!(rest.diagnose().is_empty());
+        assert_eq!(rest.deq(), Some((10, &ids[1])));
+        assert_eq!(rest.deq(), Some((20, &ids[2])));
+        assert_eq!(rest.deq(), Some((30, &ids[3])));
+
+        let mut single = SortedQueue::new(false);
+        single.enq(7, &ids[0]);
+        let (best, worst, rest) = single.take_extremes();
+        assert_eq!(best, Some((7, &ids[0])));
+        assert_eq!(worst, None);
+        assert_eq!(rest.size(), 0);
+
+        let empty: SortedQueue<u64, u64> = SortedQueue::new(false);
+        let (best, worst, _) = empty.take_extremes();
+        assert_eq!((best, worst), (None, None));
+    }
+
+    #[test]
+    fn priority_in_range() {
+        let ids: Vec<u64> = (0..5).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id * 10, id);
+        }
+        assert!(queue.any_in_range(15, 25));
+        assert!(queue.any_in_range(40, 40));
+        assert!(!queue.any_in_range(11, 19));
+        assert!(!queue.any_in_range(41, 100));
+    }
+
+    #[test]
+    fn shrink_only_map() {
+        let ids: Vec<u64> = (0..1000).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        queue.deq_until_size(10);
+        let before = queue.capacity_report();
+        queue.shrink_map_to_fit();
+        let after = queue.capacity_report();
+        assert!(after.map < before.map);
+        assert!(after.map >= 10);
+        assert_eq!(after.heap, before.heap);
+        assert_eq!(queue.deq(), Some((990, &ids[990])));
+    }
+
+    #[test]
+    fn enq_replacing_existing() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut queue = SortedQueue::new(false);
+        assert_eq!(queue.enq_replacing(5, &ids[0]), None);
+        assert_eq!(queue.enq_replacing(3, &ids[1]), None);
+        assert_eq!(queue.enq_replacing(1, &ids[0]), Some(5));
+        assert_eq!(queue.size(), 2);
+        assert!(queue.diagnose().is_empty());
+        assert_eq!(queue.deq(), Some((1, &ids[0])));
+        assert_eq!(queue.deq(), Some((3, &ids[1])));
+        assert_eq!(queue.deq(), None);
+    }
+
+    #[test]
+    fn recompute_from_refs() {
+        let emp_list = [
+            Employee { name: "victor", id: 10237 },
+            Employee { name: "hugo", id: 2937 },
+            Employee { name: "marcus", id: 1902 },
+        ];
+        let mut queue = SortedQueue::new(false);
+        for emp in emp_list.iter() {
+            queue.enq(emp.id, emp);
+        }
+        queue.recompute_priorities(|emp| emp.id % 1000);
+        assert!(queue.diagnose().is_empty());
+        assert_eq!(queue.deq(), Some((237, &emp_list[0])));
+        assert_eq!(queue.deq(), Some((902, &emp_list[2])));
+        assert_eq!(queue.deq(), Some((937, &emp_list[1])));
+    }
+
+    #[test]
+    fn from_iter_orientations() {
+        let ids: Vec<u64> = (0..6).collect();
+        let pairs: Vec<(u64, &u64)> = [4, 1, 5, 0, 3, 2].iter()
+            .map(|i| (*i, &ids[*i as usize]))
+            .collect();
+        let mut min_queue = SortedQueue::from_iter_min(pairs.clone());
+        let mut max_queue = SortedQueue::from_iter_max(pairs);
+        for i in 0..6 {
+            assert_eq!(min_queue.deq(), Some((i, &ids[i as usize])));
+            assert_eq!(max_queue.deq(), Some((5 - i, &ids[5 - i as usize])));
+        }
+    }
+
+    #[test]
+    fn pop_into_array() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        let (popped, count) = queue.pop_up_to::<4>();
+        assert_eq!(count, 2);
+        assert_eq!(popped, [Some((1, &ids[1])), Some((0, &ids[0])), None, None]);
+        assert_eq!(queue.size(), 0);
+    }
+
+    #[test]
+    fn modal_priority() {
+        let ids: Vec<u64> = (0..7).collect();
+        let mut queue = SortedQueue::new(true);
+        assert_eq!(queue.mode(), None);
+        for (id, p) in ids.iter().zip([4, 9, 4, 1, 7, 4, 9]) {
+            queue.enq(p, id);
+        }
+        assert_eq!(queue.mode(), Some(4));
+        queue.change_priority(9, &ids[0]).unwrap();
+        assert_eq!(queue.mode(), Some(9));
+        queue.change_priority(1, &ids[6]).unwrap();
+        assert_eq!(queue.mode(), Some(1));
+    }
+
+    #[test]
+    fn replace_equivalent_comparator() {
+        let ids: Vec<u64> = (0..10).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter().rev() {
+            queue.enq(*id, id);
+        }
+        queue.replace_comparator_no_reheapify(|a: &u64, b: &u64| a.cmp(b));
+        queue.change_priority(20, &ids[0]).unwrap();
+        assert_eq!(queue.deq(), Some((1, &ids[1])));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn replace_incompatible_comparator() {
+        let ids: Vec<u64> = (0..10).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        queue.replace_comparator_no_reheapify(|a: &u64, b: &u64| b.cmp(a));
+    }
+
+    #[test]
+    fn count_growth_events() {
+        let ids: Vec<u64> = (0..100).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        let doublings = (100f64 / 4.0).log2().floor() as usize;
+        assert!(queue.growth_events() >= doublings);
+        queue.reset_growth_events();
+        assert_eq!(queue.growth_events(), 0);
+        queue.deq();
+        queue.enq(0, &ids[0]);
+        assert_eq!(queue.growth_events(), 0);
+    }
+
+    #[test]
+    fn remove_by_rank() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        assert_eq!(queue.remove_at_rank(2), Some((3, &ids[3])));
+        assert_eq!(queue.remove_at_rank(5), None);
+        assert!(queue.diagnose().is_empty());
+        let mut drained = vec![];
+        while let Some((p, _)) = queue.deq() {
+            drained.push(p);
+        }
+        assert_eq!(drained, vec![5, 4, 2, 1, 0]);
+    }
+
+    #[test]
+    fn cached_sorted_slice() {
+        let ids: Vec<u64> = (0..5).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter().rev() {
+            queue.enq(*id, id);
+        }
+        let expected: Vec<(u64, &u64)> = ids.iter().map(|id| (*id, id)).collect();
+        assert_eq!(queue.sorted_slice(), expected.as_slice());
+        assert_eq!(queue.sorted_slice(), expected.as_slice());
+        assert_eq!(queue.sorted_builds(), 1);
+
+        queue.change_priority(10, &ids[0]).unwrap();
+        assert_eq!(queue.sorted_slice()[4], (10, &ids[0]));
+        assert_eq!(queue.sorted_builds(), 2);
+        queue.deq();
+        assert_eq!(queue.sorted_slice()[0], (2, &ids[2]));
+        assert_eq!(queue.sorted_builds(), 3);
+    }
+
+    #[test]
+    fn partition_by_parity() {
+        let ids: Vec<u64> = (0..10).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        let (mut even, mut odd) = queue.partition(|p, _| p % 2 == 0);
+        assert_eq!(even.size() + odd.size(), 10);
+        let mut drained = vec![];
+        while let Some((p, _)) = even.deq() {
+            drained.push(p);
+        }
+        assert_eq!(drained, vec![8, 6, 4, 2, 0]);
+        drained.clear();
+        while let Some((p, _)) = odd.deq() {
+            drained.push(p);
+        }
+        assert_eq!(drained, vec![9, 7, 5, 3, 1]);
+    }
+
+    #[test]
+    fn decay_priorities() {
+        let ids: Vec<u64> = (0..4).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(OrderedFloat(*id as f64 * 3.0), id);
+        }
+        queue.decay(0.5);
+        for id in ids.iter() {
+            assert_eq!(queue.get_weight(id), Some(OrderedFloat(*id as f64 * 1.5)));
+        }
+        assert_eq!(queue.deq(), Some((OrderedFloat(4.5), &ids[3])));
+        assert_eq!(queue.deq(), Some((OrderedFloat(3.0), &ids[2])));
+        assert_eq!(queue.deq(), Some((OrderedFloat(1.5), &ids[1])));
+        assert_eq!(queue.deq(), Some((OrderedFloat(0.0), &ids[0])));
+    }
+
+    #[test]
+    fn contains_exact_set() {
+        let ids: Vec<u64> = (0..4).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter().take(3) {
+            queue.enq(*id, id);
+        }
+        assert!(queue.contains_exactly(&[&ids[2], &ids[0], &ids[1]]));
+        assert!(!queue.contains_exactly(&[&ids[0], &ids[1], &ids[2], &ids[3]]));
+        assert!(!queue.contains_exactly(&[&ids[0], &ids[1]]));
+    }
+
+    #[test]
+    fn change_through_handle() {
+        let ids: Vec<u64> = (0..8).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter().skip(1) {
+            queue.enq(*id * 10, id);
+        }
+        let handle = queue.enq_handle(35, &ids[0]);
+        for value in [75, 5, 45, 100, 1] {
+            queue.change_by_handle(&handle, value).unwrap();
+            assert_eq!(queue.get_weight(&ids[0]), Some(value));
+            assert!(queue.diagnose().is_empty());
+        }
+        assert_eq!(queue.deq(), Some((1, &ids[0])));
+        assert!(queue.change_by_handle(&handle, 3).is_err());
+        queue.enq(50, &ids[0]);
+        queue.change_by_handle(&handle, 0).unwrap();
+        assert_eq!(queue.deq(), Some((0, &ids[0])));
+    }
+
+    #[test]
+    fn reverse_sorted_iteration() {
+        let ids: Vec<u64> = (0..7).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in [3, 6, 0, 2, 5, 1, 4] {
+            queue.enq(id, &ids[id as usize]);
+        }
+        let reversed: Vec<(u64, &u64)> = queue.sorted_iter_rev().collect();
+        let mut forward = queue.sorted_slice().to_vec();
+        forward.reverse();
+        assert_eq!(reversed, forward);
+        assert_eq!(reversed[0], (0, &ids[0]));
+        assert_eq!(queue.size(), 7);
+    }
+
+    #[test]
+    fn extend_with_map() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter().take(3) {
+            queue.enq(*id * 10, id);
+        }
+        let mut priorities = HashMap::new();
+        priorities.insert(&ids[1], 45);
+        priorities.insert(&ids[3], 5);
+        priorities.insert(&ids[4], 15);
+        queue.extend_from_map(priorities);
+        assert_eq!(queue.size(), 5);
+        let mut drained = vec![];
+        while let Some((p, id)) = queue.deq() {
+            drained.push((p, *id));
+        }
+        assert_eq!(drained, vec![(0, 0), (5, 3), (15, 4), (20, 2), (45, 1)]);
+    }
+
+    #[test]
+    fn float_total_order() {
+        let ids: Vec<u64> = (0..5).collect();
+        let values = [1.0, f64::NAN, -0.0, 0.0, f64::INFINITY];
+        let mut queue = SortedQueue::new_float(false);
+        for (value, id) in values.iter().zip(ids.iter()) {
+            queue.enq(OrderedFloat(*value), id);
+        }
+        let mut expected = values.to_vec();
+        expected.sort_by(|a, b| a.total_cmp(b));
+        for value in expected {
+            let (p, _) = queue.deq().unwrap();
+            assert_eq!(p.0.to_bits(), value.to_bits());
+        }
+    }
+
+    #[test]
+    fn drains_to_expected() {
+        let ids: Vec<u64> = (0..4).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        queue.assert_drains_to(&[(3, &ids[3]), (2, &ids[2]), (1, &ids[1]), (0, &ids[0])]);
+    }
+
+    #[test]
+    #[should_panic(expected = "did not drain as expected")]
+    fn drains_to_mismatch() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        queue.assert_drains_to(&[(2, &ids[2]), (0, &ids[0]), (1, &ids[1])]);
+    }
+
+    #[test]
+    fn reserve_before_merge() {
+        let ids: Vec<u64> = (0..1010).collect();
+        let mut queue = SortedQueue::new(false);
+        let mut other = SortedQueue::new(false);
+        for id in ids.iter().take(10) {
+            queue.enq(*id, id);
+        }
+        for id in ids.iter().skip(10) {
+            other.enq(*id, id);
+        }
+        queue.reserve_for(&other);
+        let report = queue.capacity_report();
+        assert!(report.heap >= 1010);
+        assert!(report.map >= 1010);
+        let merged = queue.merged(other);
+        assert_eq!(merged.capacity_report().heap, report.heap);
+        assert_eq!(merged.size(), 1010);
+    }
+
+    #[test]
+    fn gaps_between_priorities() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut min_queue = SortedQueue::new(false);
+        let mut max_queue = SortedQueue::new(true);
+        for (p, id) in [10u64, 1, 4].iter().zip(ids.iter()) {
+            min_queue.enq(*p, id);
+            max_queue.enq(*p, id);
+        }
+        assert_eq!(min_queue.sorted_gaps(), vec![3, 6]);
+        assert_eq!(max_queue.sorted_gaps(), vec![6, 3]);
+        assert_eq!(min_queue.size(), 3);
+    }
+
+    #[test]
+    fn deq_with_notification() {
+        let ids: Vec<u64> = (0..5).collect();
+        let mut queue = SortedQueue::new(false);
+        let mut in_queue = HashSet::new();
+        for id in ids.iter() {
+            queue.enq(*id, id);
+            in_queue.insert(id);
+        }
+        for _ in 0..3 {
+            queue.deq_notify(|id| {
+                in_queue.remove(id);
+            });
+            assert_eq!(in_queue.len(), queue.size());
+            assert!(in_queue.iter().all(|id| queue.get_weight(id).is_some()));
+        }
+        assert!(queue.contains_exactly(&[&ids[3], &ids[4]]));
+    }
+
+    #[test]
+    fn ingest_batches() {
+        let ids: Vec<u64> = (0..300).collect();
+        for batch_size in [5, 150] {
+            let mut queue = SortedQueue::new(false);
+            let mut reference = SortedQueue::new(false);
+            for id in ids.iter().take(150) {
+                queue.enq(*id * 7 % 150, id);
+                reference.enq(*id * 7 % 150, id);
+            }
+            let batch: Vec<(u64, &u64)> = ids.iter()
+                .skip(150)
+                .take(batch_size)
+                .map(|id| (*id - 140, id))
+                .chain([(1000, &ids[3])])
+                .collect();
+            queue.ingest_batch(batch.clone());
+            let mut appended = reference.into_vec_and_reuse();
+            appended.retain(|(_, id)| **id != 3);
+            appended.extend(batch);
+            let reference = SortedQueue::from_iter_min(appended);
+            assert!(queue.diagnose().is_empty());
+            assert_eq!(queue.size(), reference.size());
+            assert_eq!(queue.get_weight(&ids[3]), Some(1000));
+            let priorities = |q: &SortedQueue<u64, u64>| -> Vec<u64> {
+                q.sorted_slice().iter().map(|(p, _)| *p).collect()
+            };
+            assert_eq!(priorities(&queue), priorities(&reference));
+        }
+    }
+
+    #[test]
+    fn ingest_batch_comparisons() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let ids: Vec<u64> = (0..10_100).collect();
+        let count = Rc::new(Cell::new(0));
+        let counting = |count: &Rc<Cell<usize>>| {
+            let count = Rc::clone(count);
+            move |a: &(u64, &u64), b: &(u64, &u64)| {
+                count.set(count.get() + 1);
+                a.0 < b.0
+            }
+        };
+        let mut queue = SortedQueue::new_by(counting(&count));
+        let mut appended = SortedQueue::new_by(counting(&count));
+        for id in ids.iter().take(10_000) {
+            queue.enq(*id * 31 % 10_000, id);
+            appended.enq(*id * 31 % 10_000, id);
+        }
+        let batch: Vec<(u64, &u64)> = ids.iter().skip(10_000).map(|id| (*id, id)).collect();
+
+        count.set(0);
+        queue.ingest_batch(batch.clone());
+        let ingest = count.get();
+        count.set(0);
+        appended.extend_from_map(batch.iter().map(|(p, id)| (*id, *p)).collect());
+        let heapify = count.get();
+        assert!(ingest * 10 < heapify, "{ingest} comparisons against {heapify}");
+        assert!(queue.diagnose().is_empty());
+        assert_eq!(queue.size(), appended.size());
+        assert_eq!(queue.kth_priority(0), appended.kth_priority(0));
+
+        let mut guarded = SortedQueue::new(false);
+        guarded.enq(5, &ids[1]);
+        for id in ids[4..24].iter() {
+            guarded.enq(*id, id);
+        }
+        guarded.set_enq_guard(|id: &u64| *id != 1);
+        guarded.ingest_batch([(0, &ids[1]), (9, &ids[3])]);
+        assert_eq!(guarded.get_weight(&ids[1]), Some(5));
+        assert_eq!(guarded.get_weight(&ids[3]), Some(9));
+    }
+
+    #[test]
+    fn both_extremes_without_mutation() {
+        let ids: Vec<u64> = (0..9).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in [4, 8, 1, 6, 0, 3, 7, 2, 5] {
+            queue.enq(id, &ids[id as usize]);
+        }
+        assert_eq!(queue.extreme(false), Some((0, &ids[0])));
+        assert_eq!(queue.extreme(true), Some((8, &ids[8])));
+        assert_eq!(queue.size(), 9);
+        assert_eq!(queue.deq(), Some((0, &ids[0])));
+        let empty: SortedQueue<u64, u64> = SortedQueue::new(true);
+        assert_eq!(empty.extreme(false), None);
+    }
+
+    #[test]
+    fn lazy_priorities() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let loads: Rc<Vec<Cell<u64>>> = Rc::new((0..16).map(|i| Cell::new(i * 10)).collect());
+        let ids: Vec<usize> = (0..4).collect();
+        let spare: Vec<usize> = vec![12, 13];
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            let loads = Rc::clone(&loads);
+            queue.enq_lazy(id, move |id: &usize| loads[*id].get());
+        }
+        assert_eq!(queue.get_weight(&ids[0]), Some(0));
+        loads[0].set(25);
+        assert_eq!(queue.get_weight(&ids[0]), Some(0));
+        queue.refresh(&ids[0]).unwrap();
+        assert_eq!(queue.get_weight(&ids[0]), Some(25));
+        let order: Vec<usize> = queue.sorted_slice().iter().map(|(_, id)| **id).collect();
+        assert_eq!(order, vec![1, 2, 0, 3]);
+
+        let (_, front) = queue.deq().unwrap();
+        assert!(queue.refresh(front).is_err());
+        queue.enq(5, &ids[1]);
+        assert!(queue.refresh(&ids[1]).is_err());
+
+        queue.set_ref(&ids[2], &spare[0]).unwrap();
+        loads[12].set(1);
+        queue.refresh(&spare[0]).unwrap();
+        assert_eq!(queue.get_weight(&spare[0]), Some(1));
+        queue.enq(7, &ids[2]);
+        assert!(queue.refresh(&ids[2]).is_err());
+
+        let mut mapping = HashMap::new();
+        mapping.insert(&ids[3], &spare[1]);
+        queue.remap_refs(&mapping).unwrap();
+        loads[13].set(0);
+        queue.refresh(&spare[1]).unwrap();
+        assert_eq!(queue.peek(), Some((0, &spare[1])));
+        queue.enq(9, &ids[3]);
+        assert!(queue.refresh(&ids[3]).is_err());
+    }
+
+    #[test]
+    fn from_comparator() {
+        let ids: Vec<u64> = (0..5).collect();
+        let mut queue = SortedQueue::from(|a: &u64, b: &u64| b.cmp(a));
+        assert_eq!(queue.size(), 0);
+        for id in [2, 4, 0, 3, 1] {
+            queue.enq(id, &ids[id as usize]);
+        }
+        for id in (0..5).rev() {
+            assert_eq!(queue.deq(), Some((id, &ids[id as usize])));
+        }
+    }
+
+    #[test]
+    fn memory_estimate_grows() {
+        let ids: Vec<u64> = (0..600).collect();
+        let mut queue = SortedQueue::new(false);
+        let mut last = queue.memory_estimate();
+        for id in ids.iter().take(100) {
+            queue.enq(*id, id);
+            let estimate = queue.memory_estimate();
+            assert!(estimate >= last);
+            last = estimate;
+        }
+        assert!(last >= 100 * std::mem::size_of::<(u64, &u64)>());
+        let mut other = SortedQueue::new(false);
+        for id in ids.iter().skip(100) {
+            other.enq(*id, id);
+        }
+        queue.reserve_for(&other);
+        assert!(queue.memory_estimate() > last);
+    }
+
+    #[test]
+    fn trim_both_ends() {
+        let ids: Vec<u64> = (0..10).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in [7, 2, 9, 0, 5, 3, 8, 1, 6, 4] {
+            queue.enq(id, &ids[id as usize]);
+        }
+        let (best, worst) = queue.trim(2);
+        assert_eq!(best, vec![(0, &ids[0]), (1, &ids[1])]);
+        assert_eq!(worst, vec![(9, &ids[9]), (8, &ids[8])]);
+        assert!(queue.diagnose().is_empty());
+        let middle: Vec<u64> = queue.sorted_slice().iter().map(|(p, _)| *p).collect();
+        assert_eq!(middle, vec![2, 3, 4, 5, 6, 7]);
+
+        let (best, worst) = queue.trim(4);
+        assert_eq!(best.len(), 4);
+        assert_eq!(worst, vec![(7, &ids[7]), (6, &ids[6])]);
+        assert_eq!(queue.size(), 0);
+    }
+
+    #[test]
+    fn build_configured_queue() {
+        let ids: Vec<u64> = (0..5).collect();
+        let mut queue = SortedQueue::builder().max().capacity(64).build();
+        assert!(queue.capacity_report().heap >= 64);
+        assert!(queue.capacity_report().map >= 64);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        assert_eq!(queue.deq(), Some((4, &ids[4])));
+
+        let mut custom = SortedQueue::builder()
+            .comparator(|a: &u64, b: &u64| (a % 3).cmp(&(b % 3)).then(a.cmp(b)))
+            .build();
+        for id in ids.iter() {
+            custom.enq(*id, id);
+        }
+        let order: Vec<u64> = custom.sorted_slice().iter().map(|(p, _)| *p).collect();
+        assert_eq!(order, vec![0, 3, 1, 4, 2]);
+
+        let mut plain: SortedQueue<u64, u64> = SortedQueue::builder().build();
+        plain.enq(3, &ids[3]);
+        plain.enq(1, &ids[1]);
+        assert_eq!(plain.deq(), Some((1, &ids[1])));
+    }
+
+    #[test]
+    fn retain_and_reweight() {
+        let ids: Vec<u64> = (0..10).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        queue.retain_map(|p, _| if p % 2 == 0 { Some(p * 2) } else { None });
+        assert!(queue.diagnose().is_empty());
+        assert_eq!(queue.get_weight(&ids[3]), None);
+        let mut drained = vec![];
+        while let Some((p, id)) = queue.deq() {
+            drained.push((p, *id));
+        }
+        assert_eq!(drained, vec![(16, 8), (12, 6), (8, 4), (4, 2), (0, 0)]);
+    }
+
+    #[test]
+    fn front_identity_token() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut queue = SortedQueue::new(false);
+        assert_eq!(queue.front_ref_ptr(), None);
+        queue.enq(5, &ids[0]);
+        queue.enq(7, &ids[1]);
+        let seen = queue.front_ref_ptr();
+        queue.change_priority(6, &ids[1]).unwrap();
+        assert_eq!(queue.front_ref_ptr(), seen);
+        queue.enq(1, &ids[2]);
+        assert_ne!(queue.front_ref_ptr(), seen);
+        assert_eq!(queue.front_ref_ptr(), Some(&ids[2] as *const u64));
+    }
+
+    #[test]
+    fn repair_corruption() {
+        let ids: Vec<u64> = (0..8).collect();
+        let orphan = 99u64;
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        assert!(queue.verify_and_repair().is_empty());
+
+        queue.heap_mut()[6].0 = 0;
+        queue.heap_mut().push((3, &ids[5]));
+        queue.heap_mut().push((1, &orphan));
+        queue.map_mut().insert(&ids[2], 7);
+        let report = queue.verify_and_repair();
+        assert!(report.heap_reordered);
+        assert!(report.map_rebuilt);
+        assert_eq!(report.entries_dropped, 1);
+        assert_eq!(report.orphans_dropped, 1);
+        assert!(!queue.contains(&orphan));
+        assert!(queue.diagnose().is_empty());
+        assert!(queue.verify_and_repair().is_empty());
+        assert_eq!(queue.size(), 8);
+
+        assert_eq!(queue.get_weight(&ids[5]), Some(5));
+        let mut drained = vec![];
+        while let Some((p, _)) = queue.deq() {
+            drained.push(p);
+        }
+        assert_eq!(drained, vec![0, 0, 1, 2, 3, 4, 5, 7]);
+    }
+
+    #[test]
+    fn peek_front() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut queue = SortedQueue::new(true);
+        assert_eq!(queue.peek(), None);
+        assert_eq!(queue.peek_weight(), None);
+        queue.enq(4, &ids[0]);
+        assert_eq!(queue.peek(), Some((4, &ids[0])));
+        assert_eq!(queue.peek_weight(), Some(4));
+        assert_eq!(queue.size(), 1);
+        queue.enq(9, &ids[1]);
+        assert_eq!(queue.peek(), Some((9, &ids[1])));
+        assert_eq!(queue.deq(), Some((9, &ids[1])));
+    }
+
+    #[test]
+    fn drain_from_both_ends() {
+        let ids: Vec<u64> = (0..7).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in [5, 1, 6, 0, 3, 2, 4] {
+            queue.enq(id, &ids[id as usize]);
+        }
+        let mut drain = queue.drain_sorted();
+        assert_eq!(drain.len(), 7);
+        let mut order = vec![];
+        while let Some((p, _)) = drain.next() {
+            order.push(p);
+            if let Some((p, _)) = drain.next_back() {
+                order.push(p);
+            }
+        }
+        assert_eq!(order, vec![0, 6, 1, 5, 2, 4, 3]);
+        assert_eq!(drain.len(), 0);
+
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        let drain = queue.drain_sorted();
+        let mut collected = Vec::with_capacity(drain.len());
+        let capacity = collected.capacity();
+        collected.extend(drain);
+        assert_eq!(collected.len(), 7);
+        assert_eq!(collected.capacity(), capacity);
+        assert_eq!(collected[0], (6, &ids[6]));
+    }
+
+    #[test]
+    fn remove_by_key() {
+        let ids: Vec<u64> = (0..7).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        assert_eq!(queue.remove(&ids[1]).unwrap(), 1);
+        assert!(queue.remove(&ids[1]).is_err());
+        assert!(queue.diagnose().is_empty());
+        let last = queue.level_order().concat().last().unwrap().1;
+        queue.remove(last).unwrap();
+        assert_eq!(queue.size(), 5);
+        let mut drained = vec![];
+        while let Some((p, _)) = queue.deq() {
+            drained.push(p);
+        }
+        let mut expected = vec![0, 2, 3, 4, 5, 6];
+        expected.retain(|p| p != last);
+        assert_eq!(drained, expected);
+    }
+
+    #[test]
+    fn seeded_queue() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut queue = SortedQueue::with_initial(false, 0, &ids[0]);
+        assert_eq!(queue.size(), 1);
+        assert_eq!(queue.peek(), Some((0, &ids[0])));
+        queue.enq(5, &ids[1]);
+        assert_eq!(queue.deq(), Some((0, &ids[0])));
+    }
+
+    #[test]
+    fn bulk_from_pairs() {
+        let ids: Vec<u64> = (0..200).collect();
+        let pairs: Vec<(u64, &u64)> = ids.iter().map(|id| (*id * 37 % 101, id)).collect();
+        for max in [false, true] {
+            let mut queue = SortedQueue::from_pairs(max, pairs.clone());
+            assert_eq!(queue.size(), 200);
+            assert!(queue.diagnose().is_empty());
+            let mut last = queue.deq().unwrap().0;
+            while let Some((p, _)) = queue.deq() {
+                assert!(if max { p <= last } else { p >= last });
+                last = p;
+            }
+        }
+    }
+
+    #[test]
+    fn clear_keeps_configuration() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let ids: Vec<u64> = (0..6).collect();
+        let hasher = BuildHasherDefault::<DefaultHasher>::default();
+        let mut queue = SortedQueue::with_hasher(false, hasher);
+        queue.replace_comparator_no_reheapify(|a: &u64, b: &u64| b.cmp(a));
+        queue.set_soft_limit(2);
+        queue.set_enq_guard(|id: &u64| *id != 5);
+        for id in ids.iter() {
+            queue.enq(*id, id);
+        }
+        let capacity = queue.capacity_report();
+        queue.clear();
+        assert_eq!(queue.size(), 0);
+        assert_eq!(queue.capacity_report(), capacity);
+        for id in ids.iter().rev() {
+            queue.enq(*id, id);
+        }
+        assert!(queue.over_limit());
+        assert_eq!(queue.size(), 5);
+        let order: Vec<u64> = queue.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, vec![4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn drain_in_order() {
+        let ids: Vec<u64> = (0..8).collect();
+        let input = [6, 2, 7, 0, 4, 1, 5, 3];
+        let mut queue = SortedQueue::new(false);
+        for p in input {
+            queue.enq(p, &ids[p as usize]);
+        }
+        let first: Vec<(u64, &u64)> = {
+            let mut drain = queue.drain();
+            assert_eq!(drain.len(), 8);
+            let first = drain.by_ref().take(3).collect();
+            assert_eq!(drain.len(), 5);
+            first
+        };
+        assert_eq!(first, vec![(0, &ids[0]), (1, &ids[1]), (2, &ids[2])]);
+        assert_eq!(queue.size(), 5);
+        assert!(queue.diagnose().is_empty());
+
+        let rest: Vec<u64> = queue.drain().map(|(p, _)| p).collect();
+        let mut expected = input[..].to_vec();
+        expected.sort();
+        assert_eq!(rest, expected[3..]);
+        assert_eq!(queue.size(), 0);
+        assert!(queue.contains_exactly(&[]));
+    }
+
+    #[test]
+    fn peek_n_matches_deq() {
+        let ids: Vec<u64> = (0..1000).collect();
+        let mut queue = SortedQueue::new(true);
+        let mut copy = SortedQueue::new(true);
+        for id in ids.iter() {
+            let priority = (id * 7919) % 1000;
+            queue.enq(priority, id);
+            copy.enq(priority, id);
+        }
+        let front = queue.peek_n(3);
+        let expected: Vec<(u64, &u64)> = (0..3).map(|_| copy.deq().unwrap()).collect();
+        assert_eq!(front, expected);
+        assert_eq!(queue.size(), 1000);
+        assert!(queue.diagnose().is_empty());
+        assert_eq!(queue.peek(), Some(front[0]));
+        assert!(queue.peek_n(0).is_empty());
+        assert_eq!(queue.peek_n(2000).len(), 1000);
+    }
+
+    #[test]
+    fn new_by_breaks_ties() {
+        let staff = [
+            Employee { id: 3, name: "c" },
+            Employee { id: 1, name: "a" },
+            Employee { id: 2, name: "b" },
+            Employee { id: 4, name: "d" },
+        ];
+        let mut queue = SortedQueue::new_by(|x: &(u32, &Employee), y: &(u32, &Employee)| {
+            (x.0, x.1.id) < (y.0, y.1.id)
+        });
+        queue.enq(5, &staff[0]);
+        queue.enq(5, &staff[1]);
+        queue.enq(9, &staff[2]);
+        queue.enq(5, &staff[3]);
+        let order: Vec<u64> = queue.drain().map(|(_, e)| e.id).collect();
+        assert_eq!(order, vec![1, 3, 4, 2]);
+    }
+
+    #[test]
+    fn string_priorities() {
+        let ids: Vec<u64> = (0..4).collect();
+        let mut queue: SortedQueue<String, u64> = SortedQueue::new(false);
+        queue.enq(String::from("v1.10"), &ids[0]);
+        queue.enq(String::from("v1.2"), &ids[1]);
+        queue.enq(String::from("v0.9"), &ids[2]);
+        queue.enq(String::from("v2.0"), &ids[3]);
+        assert_eq!(queue.peek_weight(), Some(String::from("v0.9")));
+        queue.change_priority(String::from("v0.1"), &ids[3]).unwrap();
+        assert_eq!(queue.get_weight(&ids[3]), Some(String::from("v0.1")));
+        assert_eq!(queue.remove(&ids[1]).unwrap(), "v1.2");
+        let order: Vec<String> = queue.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, vec!["v0.1", "v0.9", "v1.10"]);
+    }
+
+    #[test]
+    fn owned_keys() {
+        let mut queue = OwnedSortedQueue::new(false);
+        for (priority, name) in [(4, "d"), (1, "a"), (3, "c"), (2, "b")] {
+            queue.enq(priority, String::from(name));
+        }
+        assert_eq!(queue.size(), 4);
+        assert_eq!(queue.get_weight(&String::from("c")), Some(3));
+        queue.change_priority(0, &String::from("c")).unwrap();
+        assert!(queue.change_priority(0, &String::from("z")).is_err());
+        queue.enq(9, String::from("a"));
+        assert_eq!(queue.size(), 4);
+        assert_eq!(queue.peek(), Some((0, String::from("c"))));
+        let mut order = vec![];
+        while let Some((_, name)) = queue.deq() {
+            order.push(name);
+        }
+        assert_eq!(order, vec!["c", "b", "d", "a"]);
+        assert_eq!(queue.get_weight(&String::from("a")), None);
+    }
+
+    #[test]
+    fn owned_enq_all() {
+        let mut queue = OwnedSortedQueue::new(true);
+        queue.enq(5, String::from("e"));
+        queue.enq_all([(2, "b"), (7, "g"), (1, "e"), (4, "d"), (6, "f")]
+            .into_iter()
+            .map(|(priority, name)| (priority, String::from(name))));
+        assert_eq!(queue.size(), 5);
+        assert_eq!(queue.get_weight(&String::from("e")), Some(1));
+        let mut order = vec![];
+        while let Some((_, name)) = queue.deq() {
+            order.push(name);
+        }
+        assert_eq!(order, vec!["g", "f", "d", "b", "e"]);
+    }
+
+    #[test]
+    fn enq_same_key_twice() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut queue = SortedQueue::new(false);
+        queue.enq(5, &ids[0]);
+        queue.enq(7, &ids[1]);
+        queue.enq(6, &ids[2]);
+        queue.enq(9, &ids[0]);
+        assert_eq!(queue.size(), 3);
+        assert_eq!(queue.get_weight(&ids[0]), Some(9));
+        assert!(queue.diagnose().is_empty());
+        queue.enq(1, &ids[0]);
+        assert_eq!(queue.size(), 3);
+        let drained: Vec<(u64, &u64)> = queue.drain().collect();
+        assert_eq!(drained, vec![(1, &ids[0]), (6, &ids[2]), (7, &ids[1])]);
+        assert_eq!(queue.size(), 0);
+    }
+
+    #[test]
+    fn upsert_inserts_or_updates() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut queue = SortedQueue::new(false);
+        assert!(queue.change_priority(4, &ids[0]).is_err());
+        queue.upsert(4, &ids[0]);
+        queue.upsert(8, &ids[1]);
+        assert_eq!(queue.size(), 2);
+        assert_eq!(queue.get_weight(&ids[0]), Some(4));
+        queue.upsert(2, &ids[1]);
+        assert_eq!(queue.size(), 2);
+        assert_eq!(queue.peek(), Some((2, &ids[1])));
+        queue.upsert(6, &ids[2]);
+        queue.upsert(9, &ids[1]);
+        assert!(queue.diagnose().is_empty());
+        let order: Vec<u64> = queue.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, vec![4, 6, 9]);
+
+        queue.upsert(5, &ids[1]);
+        queue.set_enq_guard(|id: &u64| *id != 1);
+        queue.upsert(0, &ids[1]);
+        assert_eq!(queue.get_weight(&ids[1]), Some(5));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let ids: Vec<u64> = (0..20).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq((id * 13) % 20, id);
+        }
+        let json = serde_json::to_string(&queue).unwrap();
+        let mut restored: OwnedSortedQueue<u64, u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.size(), 20);
+        let again = serde_json::to_string(&restored).unwrap();
+        let mut twice: OwnedSortedQueue<u64, u64> = serde_json::from_str(&again).unwrap();
+        while let Some((value, data)) = queue.deq() {
+            assert_eq!(restored.deq(), Some((value, *data)));
+            assert_eq!(twice.deq(), Some((value, *data)));
+        }
+        assert_eq!(restored.deq(), None);
+    }
+
+    #[test]
+    fn merge_in_place() {
+        let ids: Vec<u64> = (0..7).collect();
+        let mut left = SortedQueue::new(true);
+        let mut right = SortedQueue::new(true);
+        for (i, p) in [3, 9, 1].into_iter().enumerate() {
+            left.enq(p, &ids[i]);
+        }
+        for (i, p) in [7, 2, 8, 5].into_iter().enumerate() {
+            right.enq(p, &ids[i + 3]);
+        }
+        left.merge(right);
+        assert_eq!(left.size(), 7);
+        assert!(left.diagnose().is_empty());
+        let order: Vec<u64> = left.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, vec![9, 8, 7, 5, 3, 2, 1]);
+    }
+
+    #[test]
+    fn contains_and_len() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut queue = SortedQueue::new(false);
+        assert!(queue.is_empty());
+        assert_eq!(queue.len(), 0);
+        queue.enq(2, &ids[0]);
+        queue.enq(1, &ids[1]);
+        assert!(!queue.is_empty());
+        assert_eq!(queue.len(), queue.size());
+        assert!(queue.contains(&ids[0]));
+        assert!(!queue.contains(&ids[2]));
+        queue.deq();
+        assert!(!queue.contains(&ids[1]));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn bounded_keeps_top() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue = SortedQueue::with_capacity_bounded(true, 3);
+        let mut evicted = vec![];
+        for id in ids.iter() {
+            if let Some(entry) = queue.enq(*id, id) {
+                evicted.push(entry.0);
+            }
+        }
+        assert_eq!(queue.size(), 3);
+        assert_eq!(evicted, vec![0, 1, 2]);
+        assert_eq!(queue.enq(1, &ids[1]), Some((1, &ids[1])));
+        assert!(queue.diagnose().is_empty());
+        let kept: Vec<u64> = queue.drain().map(|(p, _)| p).collect();
+        assert_eq!(kept, vec![5, 4, 3]);
+
+        let mut built = SortedQueue::builder().min().bounded(2).build();
+        built.enq(4, &ids[0]);
+        built.enq(1, &ids[1]);
+        assert_eq!(built.enq(3, &ids[2]), Some((4, &ids[0])));
+        assert!(built.contains_exactly(&[&ids[1], &ids[2]]));
+    }
+
+    #[test]
+    fn into_sorted_vec_orders() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut min_queue = SortedQueue::new(false);
+        let mut max_queue = SortedQueue::new(true);
+        for (id, p) in ids.iter().zip([4, 0, 5, 2, 3, 1]) {
+            min_queue.enq(p, id);
+            max_queue.enq(p, id);
+        }
+        let ascending: Vec<u64> = min_queue.into_sorted_vec().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(ascending, vec![0, 1, 2, 3, 4, 5]);
+        let descending = max_queue.into_sorted_vec();
+        assert_eq!(descending[0], (5, &ids[2]));
+        assert!(descending.windows(2).all(|pair| pair[0].0 > pair[1].0));
+        let empty: SortedQueue<u64, u64> = SortedQueue::new(true);
+        assert!(empty.into_sorted_vec().is_empty());
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let ids: Vec<u64> = (0..4).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id * 10, id);
+        }
+        let mut snapshot = queue.clone();
+        snapshot.change_priority(99, &ids[0]).unwrap();
+        snapshot.deq();
+        assert_eq!(queue.get_weight(&ids[0]), Some(0));
+        assert_eq!(snapshot.get_weight(&ids[0]), Some(99));
+        assert_eq!(queue.size(), 4);
+        assert_eq!(snapshot.size(), 3);
+        assert_eq!(queue.peek(), Some((0, &ids[0])));
+        assert!(snapshot.diagnose().is_empty());
+    }
+
+    #[test]
+    fn retain_drops_evens() {
+        let ids: Vec<u64> = (0..10).collect();
+        let mut queue = SortedQueue::new(false);
+        for (id, p) in ids.iter().zip([7, 2, 9, 4, 0, 5, 8, 1, 6, 3]) {
+            queue.enq(p, id);
+        }
+        queue.retain(|p, _| p % 2 == 1);
+        assert_eq!(queue.size(), 5);
+        assert!(queue.diagnose().is_empty());
+        assert!(!queue.contains(&ids[1]));
+        let order: Vec<u64> = queue.clone().drain().map(|(p, _)| p).collect();
+        assert_eq!(order, vec![1, 3, 5, 7, 9]);
+        queue.retain(|_, _| false);
+        assert!(queue.is_empty());
+        assert!(queue.contains_exactly(&[]));
+    }
+
+    #[test]
+    fn new_min_sifts_to_root() {
+        let ids: Vec<u64> = (0..16).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter().take(15) {
+            queue.enq(*id + 100, id);
+        }
+        queue.enq(0, &ids[15]);
+        assert_eq!(queue.peek(), Some((0, &ids[15])));
+        queue.change_priority(1, &ids[14]).unwrap();
+        assert!(queue.diagnose().is_empty());
+        assert_eq!(queue.deq(), Some((0, &ids[15])));
+        assert_eq!(queue.deq(), Some((1, &ids[14])));
+        assert_eq!(queue.peek_weight(), Some(100));
+    }
+
+    #[test]
+    fn debug_shows_heap() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut queue = SortedQueue::new(true);
+        queue.enq(17, &ids[0]);
+        queue.enq(42, &ids[1]);
+        queue.enq(23, &ids[2]);
+        let text = format!("{:?}", queue);
+        assert!(text.starts_with("SortedQueue"));
+        assert!(text.contains("max: true"));
+        assert!(text.contains("size: 3"));
+        assert!(text.contains("(42, 1)"));
+        assert!(text.contains("17") && text.contains("23"));
+    }
+
+    #[test]
+    fn update_with_moves_entry() {
+        let ids: Vec<u64> = (0..5).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id * 10, id);
+        }
+        queue.update_with(&ids[0], |p| p + 25).unwrap();
+        assert!(queue.diagnose().is_empty());
+        assert_eq!(queue.peek(), Some((10, &ids[1])));
+        assert_eq!(queue.get_weight(&ids[0]), Some(25));
+        queue.update_with(&ids[4], |p| p - 35).unwrap();
+        assert_eq!(queue.peek(), Some((5, &ids[4])));
+        assert!(queue.update_with(&5, |p| p).is_err());
+        let order: Vec<u64> = queue.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, vec![5, 10, 20, 25, 30]);
+    }
+
+    #[test]
+    fn f64_priorities() {
+        let ids: Vec<u64> = (0..6).collect();
+        let weights = [2.5, f64::NAN, -1.0, 7.25, f64::NAN, 0.0];
+        let mut min_queue = SortedQueue::new(false);
+        let mut max_queue = SortedQueue::new(true);
+        for (id, w) in ids.iter().zip(weights) {
+            min_queue.enq(w, id);
+            max_queue.enq(w, id);
+        }
+        assert!(min_queue.diagnose().is_empty());
+        let ascending: Vec<f64> = min_queue.drain().map(|(w, _)| w).collect();
+        assert_eq!(ascending[..4], [-1.0, 0.0, 2.5, 7.25]);
+        assert!(ascending[4..].iter().all(|w| w.is_nan()));
+        let descending: Vec<f64> = max_queue.drain().map(|(w, _)| w).collect();
+        assert_eq!(descending[..4], [7.25, 2.5, 0.0, -1.0]);
+        assert!(descending[4..].iter().all(|w| w.is_nan()));
+    }
+
+    #[test]
+    fn enq_all_and_extend() {
+        let ids: Vec<u64> = (0..8).collect();
+        let priorities = [6, 3, 7, 0, 5, 2, 4, 1];
+        let mut queue = SortedQueue::new(false);
+        queue.enq_all(ids.iter().take(4).map(|id| (priorities[*id as usize], id)));
+        assert!(queue.diagnose().is_empty());
+        queue.extend(ids.iter().skip(4).map(|id| (priorities[*id as usize], id)));
+        assert_eq!(queue.size(), 8);
+        assert!(queue.diagnose().is_empty());
+        let order: Vec<u64> = queue.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, (0..8).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn deq_while_threshold() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id * 10, id);
+        }
+        let due = queue.deq_while(|time, _| time <= 25);
+        assert_eq!(due, vec![(0, &ids[0]), (10, &ids[1]), (20, &ids[2])]);
+        assert_eq!(queue.peek(), Some((30, &ids[3])));
+        assert!(queue.deq_while(|time, _| time < 30).is_empty());
+        assert_eq!(queue.size(), 3);
+        assert_eq!(queue.deq_while(|_, _| true).len(), 3);
+        assert!(queue.is_empty());
+        assert!(queue.deq_while(|_, _| true).is_empty());
+    }
+
+    #[test]
+    fn capacity_controls() {
+        let ids: Vec<u64> = (0..100).collect();
+        let mut queue = SortedQueue::with_capacity(false, 64);
+        assert!(queue.capacity() >= 64);
+        queue.reserve(100);
+        assert!(queue.capacity() >= 100);
+        for id in ids.iter().take(10) {
+            queue.enq(*id, id);
+        }
+        assert_eq!(queue.growth_events(), 0);
+        queue.shrink_to_fit();
+        assert!(queue.capacity() >= 10);
+        assert!(queue.capacity() < 100);
+        assert_eq!(queue.size(), 10);
+        assert!(queue.diagnose().is_empty());
+    }
+
+    #[test]
+    fn reverse_order_flips() {
+        let ids: Vec<u64> = (0..7).collect();
+        let mut queue = SortedQueue::new(true);
+        for (id, p) in ids.iter().zip([4.0, 1.0, f64::NAN, 6.0, 3.0, 0.0, 5.0]) {
+            queue.enq(p, id);
+        }
+        assert_eq!(queue.peek_weight(), Some(6.0));
+        queue.reverse_order();
+        assert!(queue.diagnose().is_empty());
+        let ascending: Vec<f64> = queue.clone().drain().map(|(p, _)| p).collect();
+        assert_eq!(ascending[..6], [0.0, 1.0, 3.0, 4.0, 5.0, 6.0]);
+        assert!(ascending[6].is_nan());
+        queue.reverse_order();
+        assert_eq!(queue.peek_weight(), Some(6.0));
+
+        let mut custom: SortedQueue<u64, u64> = SortedQueue::from(|a: &u64, b: &u64| b.cmp(a));
+        for id in ids.iter() {
+            custom.enq(*id, id);
+        }
+        custom.reverse_order();
+        let order: Vec<u64> = custom.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, (0..7).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn replace_priority_returns_old() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id * 10, id);
+        }
+        assert_eq!(queue.replace_priority(50, &ids[0]).unwrap(), 0);
+        assert_eq!(queue.replace_priority(5, &ids[0]).unwrap(), 50);
+        assert_eq!(queue.get_weight(&ids[0]), Some(5));
+        assert!(queue.replace_priority(1, &7).is_err());
+        assert_eq!(queue.peek(), Some((20, &ids[2])));
+    }
+
+    #[test]
+    fn stable_ties_in_insertion_order() {
+        let ids: Vec<u64> = (0..8).collect();
+        let mut queue = SortedQueue::new_stable(false);
+        for id in [5, 2, 7, 0, 3, 6, 1, 4] {
+            queue.enq(if id == 6 { 0 } else { 1 }, &ids[id]);
+        }
+        queue.change_priority(1, &ids[7]).unwrap();
+        let order: Vec<u64> = queue.clone().drain().map(|(_, id)| *id).collect();
+        assert_eq!(order, vec![6, 5, 2, 7, 0, 3, 1, 4]);
+        queue.remove(&ids[2]).unwrap();
+        queue.enq(1, &ids[2]);
+        let order: Vec<u64> = queue.drain().map(|(_, id)| *id).collect();
+        assert_eq!(order, vec![6, 5, 7, 0, 3, 1, 4, 2]);
+
+        let mut built = SortedQueue::builder().max().stable().build();
+        built.enq_all(ids.iter().map(|id| (id % 2, id)));
+        let odds: Vec<u64> = built.deq_while(|p, _| p == 1).into_iter().map(|(_, id)| *id).collect();
+        assert_eq!(odds, vec![1, 3, 5, 7]);
+    }
+
+    #[test]
+    fn stable_stamps_wrap() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue = SortedQueue::new_stable(false);
+        queue.set_next_seq(u64::MAX - 3);
+        for id in [4, 1, 5, 0, 3, 2] {
+            queue.enq(if id == 3 { 0 } else { 1 }, &ids[id]);
+        }
+        assert!(queue.diagnose().is_empty());
+        let order: Vec<u64> = queue.drain().map(|(_, id)| *id).collect();
+        assert_eq!(order, vec![3, 4, 1, 5, 0, 2]);
+    }
+
+    #[test]
+    fn refill_after_clear() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue = SortedQueue::builder().max().bounded(4).stable().build();
+        for id in ids.iter() {
+            queue.enq(id % 3, id);
+        }
+        queue.clear();
+        assert_eq!(queue.size(), 0);
+        assert_eq!(queue.deq(), None);
+        assert!(!queue.contains(&ids[0]));
+        assert!(queue.change_priority(1, &ids[0]).is_err());
+        assert!(queue.diagnose().is_empty());
+
+        let mut fresh = SortedQueue::builder().max().bounded(4).stable().build();
+        for id in ids.iter().rev() {
+            assert_eq!(queue.enq(id % 3, id), fresh.enq(id % 3, id));
+        }
+        assert_eq!(queue.size(), 4);
+        assert_eq!(queue.into_sorted_vec(), fresh.into_sorted_vec());
+    }
+
+    #[test]
+    fn check_invariants_reports_first() {
+        let ids: Vec<u64> = (0..7).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            queue.enq(*id * 10, id);
+        }
+        assert_eq!(queue.check_invariants(), Ok(()));
+        queue.heap_mut()[5].0 = 1;
+        queue.heap_mut()[3].0 = 0;
+        assert_eq!(
+            queue.check_invariants(),
+            Err(String::from("heap slot 3 comes out before its parent at slot 1"))
+        );
+        queue.heap_mut()[5].0 = 50;
+        queue.heap_mut()[3].0 = 30;
+        queue.map_mut().insert(&ids[2], 6);
+        assert_eq!(
+            queue.check_invariants(),
+            Err(String::from("map points a reference at heap slot 6, which does not hold it"))
+        );
+        queue.verify_and_repair();
+        assert!(queue.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn replace_root_single_sift() {
+        let ids: Vec<u64> = (0..8).collect();
+        let mut queue = SortedQueue::new(false);
+        assert_eq!(queue.replace_root(5, &ids[0]), None);
+        assert_eq!(queue.size(), 1);
+        for id in ids.iter().skip(1).take(5) {
+            queue.enq(*id * 10, id);
+        }
+        assert_eq!(queue.replace_root(35, &ids[6]), Some((5, &ids[0])));
+        assert!(queue.diagnose().is_empty());
+        assert!(!queue.contains(&ids[0]));
+        assert_eq!(queue.get_weight(&ids[6]), Some(35));
+        assert_eq!(queue.replace_root(1, &ids[7]), Some((10, &ids[1])));
+        assert_eq!(queue.peek(), Some((1, &ids[7])));
+        assert_eq!(queue.replace_root(45, &ids[7]), Some((1, &ids[7])));
+        assert!(queue.diagnose().is_empty());
+        let order: Vec<u64> = queue.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, vec![20, 30, 35, 40, 45, 50]);
+    }
+
+    #[test]
+    fn iter_visits_every_entry() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue = SortedQueue::new(true);
+        for id in ids.iter() {
+            queue.enq(*id * 3, id);
+        }
+        assert_eq!(queue.iter().len(), 6);
+        let keys: HashSet<&u64> = queue.iter().map(|(_, id)| id).collect();
+        assert_eq!(keys, ids.iter().collect::<HashSet<&u64>>());
+        assert!(queue.iter().all(|(p, id)| p == *id * 3));
+        assert_eq!(queue.size(), 6);
+        assert_eq!(queue.peek(), Some((15, &ids[5])));
+    }
+
+    #[test]
+    fn offset_all_keeps_order() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue = SortedQueue::new(false);
+        for (id, p) in ids.iter().zip([5, 1, 4, 0, 3, 2]) {
+            queue.enq(p, id);
+        }
+        let before: Vec<&u64> = queue.sorted_slice().iter().map(|(_, id)| *id).collect();
+        queue.offset_all(10);
+        assert_eq!(queue.get_weight(&ids[0]), Some(15));
+        assert_eq!(queue.get_weight(&ids[3]), Some(10));
+        assert!(queue.diagnose().is_empty());
+        let drained: Vec<(u64, &u64)> = queue.drain().collect();
+        let after: Vec<&u64> = drained.iter().map(|(_, id)| *id).collect();
+        assert_eq!(after, before);
+        assert_eq!(drained[0].0, 10);
+    }
+
+    #[test]
+    fn bounded_bulk_load() {
+        let ids: Vec<u64> = (0..5000).collect();
+        let mut queue = SortedQueue::builder().max().bounded(10).build();
+        queue.enq_all(ids.iter().map(|id| ((id * 7919) % 5000, id)));
+        assert_eq!(queue.size(), 10);
+        assert!(queue.diagnose().is_empty());
+        let kept: Vec<u64> = queue.clone().drain().map(|(p, _)| p).collect();
+        assert_eq!(kept, (4990..5000).rev().collect::<Vec<u64>>());
+        let mut other = SortedQueue::new(true);
+        other.enq_all(ids.iter().take(20).map(|id| (*id + 10000, id)));
+        queue.merge(other);
+        assert_eq!(queue.size(), 10);
+        assert_eq!(queue.peek_weight(), Some(10019));
+        assert!(queue.diagnose().is_empty());
+    }
+
+    #[test]
+    fn partition_keeps_ordering() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut custom: SortedQueue<u64, u64> = SortedQueue::from(|a: &u64, b: &u64| b.cmp(a));
+        for id in ids.iter() {
+            custom.enq(*id, id);
+        }
+        let (evens, odds) = custom.partition(|p, _| p % 2 == 0);
+        let evens: Vec<u64> = evens.into_sorted_vec().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(evens, vec![4, 2, 0]);
+        let mut odds = odds;
+        odds.reverse_order();
+        let odds: Vec<u64> = odds.into_sorted_vec().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(odds, vec![1, 3, 5]);
+
+        let mut stable = SortedQueue::builder().stable().bounded(4).build();
+        for id in [3, 5, 4, 0, 2, 1] {
+            stable.enq(0, &ids[id]);
+        }
+        let (low, high) = stable.partition(|_, id| *id < 4);
+        let low: Vec<u64> = low.into_sorted_vec().into_iter().map(|(_, id)| *id).collect();
+        assert_eq!(low, vec![3, 0]);
+        let mut high = high;
+        assert_eq!(high.size(), 2);
+        high.enq_all([(1, &ids[0]), (0, &ids[1]), (0, &ids[2])]);
+        assert_eq!(high.size(), 4);
+        let high: Vec<u64> = high.into_sorted_vec().into_iter().map(|(_, id)| *id).collect();
+        assert_eq!(high, vec![5, 4, 1, 2]);
+    }
+
+    #[test]
+    fn partition_keeps_lazy_priorities() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let loads: Rc<Vec<Cell<u64>>> = Rc::new((0..4).map(|i| Cell::new(i * 10)).collect());
+        let ids: Vec<usize> = (0..4).collect();
+        let mut queue = SortedQueue::new(false);
+        for id in ids.iter() {
+            let loads = Rc::clone(&loads);
+            queue.enq_lazy(id, move |id: &usize| loads[*id].get());
+        }
+        let (mut low, mut high) = queue.partition(|_, id| *id < 2);
+        loads[1].set(5);
+        loads[3].set(15);
+        low.refresh(&ids[1]).unwrap();
+        high.refresh(&ids[3]).unwrap();
+        assert!(low.refresh(&ids[3]).is_err());
+        assert!(high.refresh(&ids[1]).is_err());
+        assert_eq!(low.get_weight(&ids[1]), Some(5));
+        assert_eq!(high.peek(), Some((15, &ids[3])));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_keeps_ties_and_bound() {
+        let ids: Vec<u64> = (0..8).collect();
+        let mut stable = SortedQueue::new_stable(false);
+        for id in ids[..6].iter() {
+            stable.enq(0, id);
+        }
+        let json = serde_json::to_string(&stable).unwrap();
+        let restored: OwnedSortedQueue<u64, u64> = serde_json::from_str(&json).unwrap();
+        let again = serde_json::to_string(&restored).unwrap();
+        let mut twice: OwnedSortedQueue<u64, u64> = serde_json::from_str(&again).unwrap();
+        let mut restored = restored;
+        let mut order = vec![];
+        while let Some((_, id)) = restored.deq() {
+            assert_eq!(twice.deq(), Some((0, id)));
+            order.push(id);
+        }
+        assert_eq!(order, vec![0, 1, 2, 3, 4, 5]);
+
+        let mut bounded = SortedQueue::builder().max().bounded(3).build();
+        for id in ids.iter().take(5) {
+            bounded.enq(*id, id);
+        }
+        let json = serde_json::to_string(&bounded).unwrap();
+        let mut restored: OwnedSortedQueue<u64, u64> = serde_json::from_str(&json).unwrap();
+        restored.enq(1, 6);
+        restored.enq(7, 7);
+        assert_eq!(restored.size(), 3);
+        let order: Vec<u64> = std::iter::from_fn(|| restored.deq()).map(|(p, _)| p).collect();
+        assert_eq!(order, vec![7, 4, 3]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_rejects_custom_comparator() {
+        let ids: Vec<u64> = (0..3).collect();
+        let mut custom: SortedQueue<u64, u64> = SortedQueue::from(|a: &u64, b: &u64| b.cmp(a));
+        custom.enq(1, &ids[1]);
+        assert!(serde_json::to_string(&custom).is_err());
+
+        let mut stable = SortedQueue::new_stable(false);
+        for id in [2, 0, 1] {
+            stable.enq(0, &ids[id]);
+        }
+        let json = serde_json::to_string(&stable).unwrap();
+        assert_eq!(json, r#"{"heap":[[0,2],[0,0],[0,1]],"max":false,"stable":true,"bound":null}"#);
+        let restored: OwnedSortedQueue<u64, u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.size(), 3);
+    }
+
+    #[test]
+    fn extreme_with_custom_comparator() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut queue: SortedQueue<u64, u64> = SortedQueue::from(|a: &u64, b: &u64| b.cmp(a));
+        for id in [3, 0, 5, 1, 4, 2] {
+            queue.enq(id, &ids[id as usize]);
+        }
+        assert_eq!(queue.extreme(true), Some((5, &ids[5])));
+        assert_eq!(queue.extreme(false), Some((0, &ids[0])));
+        queue.reverse_order();
+        assert_eq!(queue.extreme(true), Some((5, &ids[5])));
+        assert_eq!(queue.extreme(false), Some((0, &ids[0])));
+        let empty: SortedQueue<u64, u64> = SortedQueue::from(|a: &u64, b: &u64| b.cmp(a));
+        assert_eq!(empty.extreme(true), None);
+
+        let mut by_residue = SortedQueue::builder()
+            .comparator(|a: &u64, b: &u64| (a % 3).cmp(&(b % 3)).then(a.cmp(b)))
+            .build();
+        for id in ids[..5].iter() {
+            by_residue.enq(*id, id);
+        }
+        assert_eq!(by_residue.extreme(true), Some((4, &ids[4])));
+        assert_eq!(by_residue.extreme(false), Some((0, &ids[0])));
+
+        let keys: Vec<u64> = (0..3).collect();
+        let mut floats = SortedQueue::builder()
+            .comparator(|a: &f64, b: &f64| b.total_cmp(a))
+            .build();
+        floats.enq(f64::NAN, &keys[0]);
+        floats.enq(2.0, &keys[1]);
+        floats.enq(-1.0, &keys[2]);
+        assert_eq!(floats.extreme(true), Some((2.0, &keys[1])));
+        assert_eq!(floats.extreme(false), Some((-1.0, &keys[2])));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "overflow")]
+    fn offset_all_overflow() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut queue = SortedQueue::new(false);
+        queue.enq(0, &ids[0]);
+        queue.enq(i32::MAX - 1, &ids[1]);
+        queue.offset_all(5);
+    }
+
+    #[test]
+    fn offset_all_rounding_in_stable_mode() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut queue = SortedQueue::new_stable(false);
+        queue.enq(1e17, &ids[0]);
+        queue.enq(1e17 - 16.0, &ids[1]);
+        queue.offset_all(1e17);
+        assert!(queue.diagnose().is_empty());
+        assert_eq!(queue.deq(), Some((2e17, &ids[0])));
+    }
+
+    #[test]
+    fn merge_applies_guard() {
+        let ids: Vec<u64> = (0..6).collect();
+        let mut left = SortedQueue::new(false);
+        left.set_enq_guard(|id: &u64| id.is_multiple_of(2));
+        left.enq(0, &ids[0]);
+        let mut right = SortedQueue::new(false);
+        for id in ids[1..].iter() {
+            right.enq(*id, id);
+        }
+        left.merge(right);
+        assert!(left.diagnose().is_empty());
+        let order: Vec<u64> = left.drain().map(|(p, _)| p).collect();
+        assert_eq!(order, vec![0, 2, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot merge a reversed queue with an unreversed one")]
+    fn merge_reversed_mismatch() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut left = SortedQueue::new_by(|a: &(u64, &u64), b: &(u64, &u64)| a.0 < b.0);
+        let mut right = SortedQueue::new_by(|a: &(u64, &u64), b: &(u64, &u64)| a.0 < b.0);
+        left.enq(0, &ids[0]);
+        right.enq(1, &ids[1]);
+        right.reverse_order();
+        left.merge(right);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot merge a custom comparator with the default one")]
+    fn merge_custom_mismatch() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut left = SortedQueue::new(false);
+        let mut right = SortedQueue::new_by(|a: &(u64, &u64), b: &(u64, &u64)| a.0 < b.0);
+        left.enq(0, &ids[0]);
+        right.enq(1, &ids[1]);
+        left.merge(right);
+    }
+
+    #[test]
+    fn merge_keeps_stable_order() {
+        let ids: Vec<u64> = (0..4).collect();
+        let mut left = SortedQueue::new_stable(false);
+        let mut right = SortedQueue::new_stable(false);
+        left.enq(1, &ids[3]);
+        right.enq(1, &ids[0]);
+        right.enq(1, &ids[1]);
+        right.enq(0, &ids[2]);
+        left.merge(right);
+        let order: Vec<u64> = left.drain().map(|(_, id)| *id).collect();
+        assert_eq!(order, vec![2, 3, 0, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot merge a stable queue with an unstable one")]
+    fn merge_stable_mismatch() {
+        let ids: Vec<u64> = (0..2).collect();
+        let mut left = SortedQueue::new_stable(false);
+        let mut right = SortedQueue::new(false);
+        left.enq(0, &ids[0]);
+        right.enq(1, &ids[1]);
+        left.merge(right);
+    }
+
+    #[test]
+    fn merge_keeps_lazy_priorities() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let loads: Rc<Vec<Cell<u64>>> = Rc::new((0..3).map(|i| Cell::new(i * 10)).collect());
+        let ids: Vec<usize> = (0..3).collect();
+        let mut left = SortedQueue::new(false);
+        let mut right = SortedQueue::new(false);
+        let load = |loads: &Rc<Vec<Cell<u64>>>| {
+            let loads = Rc::clone(loads);
+            move |id: &usize| loads[*id].get()
+        };
+        left.enq_lazy(&ids[0], load(&loads));
+        right.enq_lazy(&ids[1], load(&loads));
+        right.enq_lazy(&ids[2], load(&loads));
+        left.merge(right);
+        loads[2].set(5);
+        left.refresh(&ids[2]).unwrap();
+        assert_eq!(left.get_weight(&ids[2]), Some(5));
+        left.refresh(&ids[0]).unwrap();
+    }
+}